@@ -3,6 +3,18 @@ use std::{fmt::Display, io};
 #[derive(Debug)]
 pub enum Error {
     Other(String),
+    /// Failure of a script at the index of a line.
+    Line(usize, String),
+}
+
+impl Error {
+    /// Index of the line of the script the error is at.
+    pub fn line(&self) -> Option<usize> {
+        match self {
+            Error::Other(_) => None,
+            Error::Line(line, _) => Some(*line),
+        }
+    }
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
@@ -10,7 +22,7 @@ pub type Result<T> = std::result::Result<T, Error>;
 impl Display for Error {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            Error::Other(msg) => write!(f, "{msg}"),
+            Error::Other(msg) | Error::Line(_, msg) => write!(f, "{msg}"),
         }
     }
 }
@@ -3,15 +3,16 @@
 mod err;
 mod util;
 
-use std::{collections::BTreeMap, sync::Arc};
+use std::{
+    collections::BTreeMap,
+    io::{self, Read},
+    sync::Arc,
+};
 
 use earth::AsConfig;
-use edge_lib::util::{
-    data::MemDataManager,
-    engine::{AsEdgeEngine, EdgeEngine},
-};
+use edge_lib::util::data::MemDataManager;
 use tokio::sync::Mutex;
-use util::{connector, server};
+use util::{audit, connector, export, gossip, registry, route, server, sign, store, transaction};
 
 // Public
 #[derive(serde::Deserialize, serde::Serialize, AsConfig, Clone, Debug)]
@@ -25,7 +26,17 @@ struct Config {
     port: u16,
     /// Default: light
     path: String,
+    /// Proxy entries by path: a service name, or upstreams separated by `,`
+    /// like `http://127.0.0.1:8080` or `unix:/run/app.sock`
     proxy: BTreeMap<String, String>,
+    /// Session affinity of proxy entries, by path: light, cookie:{name} or header:{name}
+    sticky: BTreeMap<String, String>,
+    /// Instances to proxy to by path: tag:{tag}, {key}={value} or prefer:{key}, separated by `,`
+    select: BTreeMap<String, String>,
+    /// Directories served as static files by path, like `"/static" = "/var/www"`
+    mounts: BTreeMap<String, String>,
+    /// Directories mounts added at runtime may serve from, besides the dirs of `mounts`
+    mount_roots: Vec<String>,
     /// Default: info
     log_level: String,
     /// Default: dist
@@ -33,7 +44,79 @@ struct Config {
     /// Default: 8
     thread_num: u8,
     moon_servers: Vec<String>,
+    /// Discover other lights by gossip, serving `{path}/gossip`. Default: false
+    gossip: bool,
+    /// Uris of lights to start gossiping with, like `http://10.0.0.2/light`
+    gossip_seeds: Vec<String>,
+    /// Seconds between gossip rounds. Default: 1
+    gossip_interval: u64,
+    /// Seconds a service that failed to resolve is not looked up again. Default: 5
+    negative_ttl: u64,
+    /// Backends resolving proxied services, tried in order: moon, file:{path}, dns:{host}[:{port}],
+    /// srv:{host} or env[:{prefix}]. Default: ["moon"]
+    discovery: Vec<String>,
+    /// Prefix to pass requests through to moon servers, `_` to disable. Default: /moon_server
+    moon_path: String,
+    /// Who may use the passthrough: open, loopback or bearer:{token}, separated by `,`.
+    /// Default: loopback
+    moon_auth: String,
+    /// Hosts advertised to moon servers instead of the addresses of this host, separated by `,`.
+    /// Default: _
     domain: String,
+    /// Interface whose addresses are advertised, `_` for all. Default: _
+    interface: String,
+    /// Family of the advertised addresses: ipv4, ipv6 or any. Default: ipv6
+    family: String,
+    /// Scopes of the advertised addresses: global, ula, private, link or loopback.
+    /// Default: ["global"]
+    scopes: Vec<String>,
+    /// Seconds between reports to moon servers. Default: 10
+    heartbeat: u64,
+    /// Max seconds added to each heartbeat at random. Default: 2
+    heartbeat_jitter: u64,
+    /// Seconds a report stays valid on moon servers without a heartbeat. Default: 30
+    lease: u64,
+    /// Serve the registry API under `path`, so the light can be a moon server. Default: false
+    registry: bool,
+    /// Subscribe to changes of the registry on moon servers serving the registry API.
+    /// Default: false
+    watch: bool,
+    /// Scheme reported to moon servers, `unix` to serve on the socket at `ip`. Default: http
+    scheme: String,
+    /// Server name for TLS reported to moon servers. Default: _
+    tls_sni: String,
+    /// Path of a CA bundle trusted besides the system's when proxying to HTTPS upstreams.
+    /// Default: _
+    tls_ca: String,
+    /// Skip verifying HTTPS upstreams, for development only. Default: false
+    tls_insecure: bool,
+    /// Metadata reported to moon servers, like version, zone or weight
+    meta: BTreeMap<String, String>,
+    /// Tags reported to moon servers
+    tags: Vec<String>,
+    /// `{id}:{secret}` to sign calls to moon servers with, `id` being `name`. Default: _
+    moon_key: String,
+    /// `{id}:{secret}` of callers this light accepts when serving as a moon server.
+    /// Calls are not verified if empty
+    registry_keys: Vec<String>,
+    /// `{id}:{secret}` of admins allowed to execute scripts on `{path}/execute`
+    admin_keys: Vec<String>,
+    /// Hosts signed calls must be addressed to, as `{host}[:{port}]` like in the `Host` header.
+    /// The `Host` of each call if empty
+    audiences: Vec<String>,
+    /// Scopes of admins by id, like `read` or `write:proxy,write:web_server`. Default: all
+    admin_scopes: BTreeMap<String, String>,
+    /// Execute unsigned scripts on `{path}/execute`, for trusted networks only. Default: false
+    execute_open: bool,
+    /// Directory keeping changes made at runtime across restarts, `_` to keep nothing. Default: _
+    data_dir: String,
+    /// Seconds between snapshots in `data_dir`. Default: 60
+    snapshot_interval: u64,
+    /// File the audit log of changes to the graph is appended to, `_` to keep it in memory only.
+    /// Default: _
+    audit_log: String,
+    /// Latest changes kept in memory for `{path}/admin/audit`. Default: 1000
+    audit_size: usize,
 }
 
 impl Default for Config {
@@ -44,11 +127,46 @@ impl Default for Config {
             port: 80,
             path: "/light".to_string(),
             proxy: BTreeMap::new(),
+            sticky: BTreeMap::new(),
+            select: BTreeMap::new(),
+            mounts: BTreeMap::new(),
+            mount_roots: Vec::new(),
             log_level: "info".to_string(),
             src: "dist".to_string(),
             thread_num: 8,
             moon_servers: Vec::new(),
+            discovery: vec!["moon".to_string()],
+            gossip: false,
+            gossip_seeds: Vec::new(),
+            gossip_interval: 1,
+            negative_ttl: 5,
+            moon_path: "/moon_server".to_string(),
+            moon_auth: "loopback".to_string(),
             domain: format!("_"),
+            interface: format!("_"),
+            family: "ipv6".to_string(),
+            scopes: vec!["global".to_string()],
+            heartbeat: 10,
+            heartbeat_jitter: 2,
+            lease: 30,
+            registry: false,
+            watch: false,
+            scheme: "http".to_string(),
+            tls_sni: format!("_"),
+            tls_ca: format!("_"),
+            tls_insecure: false,
+            meta: BTreeMap::new(),
+            tags: Vec::new(),
+            moon_key: format!("_"),
+            registry_keys: Vec::new(),
+            admin_keys: Vec::new(),
+            audiences: Vec::new(),
+            admin_scopes: BTreeMap::new(),
+            execute_open: false,
+            data_dir: format!("_"),
+            snapshot_interval: 60,
+            audit_log: format!("_"),
+            audit_size: 1000,
         }
     }
 }
@@ -58,6 +176,7 @@ fn main() {
     let mut config = Config::default();
     let mut arg_v: Vec<String> = std::env::args().collect();
     arg_v.remove(0);
+    let command = parse_command(&mut arg_v);
     let file_name = if !arg_v.is_empty() && !arg_v[0].starts_with("--") {
         arg_v.remove(0)
     } else {
@@ -80,61 +199,332 @@ fn main() {
         .enable_all()
         .build()
         .unwrap();
+    let audit_log = if config.audit_log != "_" {
+        Some(config.audit_log.as_str())
+    } else {
+        None
+    };
+    audit::init(audit_log, config.audit_size).unwrap();
+    sign::init(sign::Keys::parse(
+        &config.moon_key,
+        &config.registry_keys,
+        &config.admin_keys,
+    ));
+
+    // Commands on the graph kept in data_dir
+    match command {
+        Command::Export(code_v) => {
+            rt.block_on(export_data(&config.data_dir, &code_v)).unwrap();
+            return;
+        }
+        Command::Import(replace) => {
+            rt.block_on(audit::scope(
+                audit::Actor::internal("import"),
+                import_data(&config.data_dir, replace),
+            ))
+            .unwrap();
+            return;
+        }
+        Command::Serve => (),
+    }
 
     rt.block_on(async {
         let mut global = MemDataManager::new(None);
 
+        // Changes made at runtime first, the config on top of them.
+        let store = if config.data_dir != "_" {
+            Some(Arc::new(
+                store::Store::open(&config.data_dir, &mut global)
+                    .await
+                    .unwrap(),
+            ))
+        } else {
+            None
+        };
+        let moon_server_v = route::list_moon_servers(&mut global).await.unwrap();
+
         {
-            let mut edge_engine = EdgeEngine::new(&mut global);
             // config.ip, config.port, config.name
-            edge_engine
-                .execute_script(&[
+            config_execute(
+                &mut global,
+                &[
                     format!("root->name = {} _", config.name),
                     format!("root->ip = {} _", config.ip),
                     format!("root->port = {} _", config.port),
                     format!("root->path = {} _", config.path),
                     format!("root->src = {} _", config.src),
                     format!("root->domain = {} _", config.domain),
-                ])
-                .await
-                .unwrap();
+                    format!("root->interface = {} _", config.interface),
+                    format!("root->family = {} _", config.family),
+                    format!("root->heartbeat = {} _", config.heartbeat),
+                    format!("root->heartbeat_jitter = {} _", config.heartbeat_jitter),
+                    format!("root->lease = {} _", config.lease),
+                    format!("root->gossip_interval = {} _", config.gossip_interval),
+                    format!("root->negative_ttl = {} _", config.negative_ttl),
+                    format!("root->registry = {} _", config.registry),
+                    format!("root->moon_path = {} _", config.moon_path),
+                    format!("root->moon_auth = {} _", config.moon_auth),
+                    format!("root->scheme = {} _", config.scheme),
+                    format!("root->tls_sni = {} _", config.tls_sni),
+                    format!("root->tls_ca = {} _", config.tls_ca),
+                    format!("root->tls_insecure = {} _", config.tls_insecure),
+                    format!("root->execute_open = {} _", config.execute_open),
+                    format!("root->snapshot_interval = {} _", config.snapshot_interval),
+                ],
+            )
+            .await
+            .unwrap();
 
-            let option_script = config
-                .moon_servers
+            // Lists of the config replace what was kept, except moon servers added at runtime.
+            // Keys kept in the graph by earlier versions are dropped, they are out of it now.
+            let option_script =
+                [
+                    "meta",
+                    "tag",
+                    "gossip_seed",
+                    "discovery",
+                    "scope",
+                    "admin_scope",
+                    "audience",
+                    "mount_root",
+                    "moon_key",
+                    "registry_key",
+                    "admin_key",
+                ]
                 .iter()
-                .map(|moon_server| {
-                    format!("root->moon_server append root->moon_server {moon_server}")
-                })
+                .map(|code| format!("root->{code} = _ _"))
+                .chain(
+                    config
+                        .moon_servers
+                        .iter()
+                        .filter(|moon_server| !moon_server_v.contains(moon_server))
+                        .map(|moon_server| {
+                            format!("root->moon_server append root->moon_server {moon_server}")
+                        })
+                        .chain(config.meta.iter().map(|(key, value)| {
+                            format!("root->meta append root->meta {key}={value}")
+                        }))
+                        .chain(
+                            config
+                                .tags
+                                .iter()
+                                .map(|tag| format!("root->tag append root->tag {tag}")),
+                        )
+                        .chain(config.gossip_seeds.iter().map(|seed| {
+                            format!("root->gossip_seed append root->gossip_seed {seed}")
+                        }))
+                        .chain(config.discovery.iter().map(|discovery| {
+                            format!("root->discovery append root->discovery {discovery}")
+                        }))
+                        .chain(
+                            config
+                                .scopes
+                                .iter()
+                                .map(|scope| format!("root->scope append root->scope {scope}")),
+                        )
+                        .chain(config.admin_scopes.iter().map(|(id, scope)| {
+                            format!("root->admin_scope append root->admin_scope {id}={scope}")
+                        }))
+                        .chain(
+                            config
+                                .audiences
+                                .iter()
+                                .map(|aud| format!("root->audience append root->audience {aud}")),
+                        )
+                        .chain(
+                            config
+                                .mount_roots
+                                .iter()
+                                .chain(config.mounts.values())
+                                .map(|dir| {
+                                    format!("root->mount_root append root->mount_root {dir}")
+                                }),
+                        ),
+                )
                 .collect::<Vec<String>>();
-            
+
             if !option_script.is_empty() {
-                edge_engine.execute_script(&option_script).await.unwrap();
+                config_execute(&mut global, &option_script).await.unwrap();
             }
 
-            let option_script1 = config
+            let route_v = config
                 .proxy
                 .into_iter()
-                .map(|(path, name)| {
-                    vec![
-                        format!("$->$:proxy = ? _"),
-                        format!("$->$:proxy->path = {path} _"),
-                        format!("$->$:proxy->name = {name} _"),
-                        format!("root->proxy append root->proxy $->$:proxy"),
-                    ]
+                .map(|(path, target)| {
+                    let mut route = route::Route {
+                        sticky: config.sticky.get(&path).cloned(),
+                        select: config.select.get(&path).cloned(),
+                        path,
+                        ..Default::default()
+                    };
+                    if target.contains("://") || target.starts_with("unix:") {
+                        route.upstreams = target.split(',').map(|s| s.trim().to_string()).collect();
+                    } else {
+                        route.name = Some(target);
+                    }
+                    // Malformed entries would fail every request, so they fail the start.
+                    route::check_route(&route).unwrap();
+                    route
                 })
+                .collect::<Vec<route::Route>>();
+            let mount_v = config
+                .mounts
+                .into_iter()
+                .map(|(path, dir)| route::Mount { path, dir })
+                .collect::<Vec<route::Mount>>();
+            let option_script1 = route_v
+                .iter()
+                .map(route::route_script)
+                .chain(mount_v.iter().map(route::mount_script))
                 .reduce(|mut acc, block| {
                     acc.extend(block);
                     acc
                 });
 
             if let Some(script) = option_script1 {
-                edge_engine.execute_script(&script).await.unwrap();
+                config_execute(&mut global, &script).await.unwrap();
+            }
+
+            // Applied again on each start, so left out of what is kept.
+            if let Some(store) = &store {
+                store.set_config(export::Export {
+                    proxy: Some(route_v),
+                    mount: Some(mount_v),
+                    moon_server: Some(config.moon_servers.clone()),
+                    ..Default::default()
+                });
             }
         }
 
         let gloabl = Arc::new(Mutex::new(global));
 
-        tokio::spawn(connector::HttpConnector::new(gloabl.clone()).run());
-        server::WebServer::new(gloabl).run().await.unwrap()
+        tokio::spawn(audit::scope(
+            audit::Actor::internal("evictor"),
+            registry::Evictor::new(gloabl.clone()).run(),
+        ));
+        if config.watch {
+            tokio::spawn(audit::scope(
+                audit::Actor::internal("watcher"),
+                connector::HttpWatcher::new(gloabl.clone()).run(),
+            ));
+        }
+        let connector_task = tokio::spawn(audit::scope(
+            audit::Actor::internal("connector"),
+            connector::HttpConnector::new(gloabl.clone()).run(),
+        ));
+        let connector = connector::HttpConnector::new(gloabl.clone());
+        let mut web_server = server::WebServer::new(gloabl.clone());
+        if config.gossip {
+            let cluster = Arc::new(gossip::Cluster::new(gloabl.clone()));
+            tokio::spawn(audit::scope(
+                audit::Actor::internal("gossip"),
+                cluster.clone().run(),
+            ));
+            web_server = web_server.gossip(cluster);
+        }
+        if let Some(store) = &store {
+            tokio::spawn(audit::scope(
+                audit::Actor::internal("store"),
+                store.clone().run(gloabl.clone()),
+            ));
+            web_server = web_server.store(store.clone());
+        }
+        web_server
+            .run(async move {
+                connector_task.abort();
+                if let Err(e) = connector.deregister().await {
+                    log::warn!("{e}\nwhen stop");
+                }
+                if let Some(store) = store {
+                    if let Err(e) = store.snapshot(&mut *gloabl.lock().await).await {
+                        log::warn!("{e}\nwhen stop");
+                    }
+                }
+            })
+            .await
+            .unwrap()
     })
 }
+
+// Private
+/// `light export [file] [--codes {code},{code}]` prints an export of the graph kept in
+/// `data_dir`, `light import [file] [--replace]` imports one from stdin into it. Both are for a
+/// stopped light, a running one serves `{path}/admin/export` and `{path}/admin/import`.
+enum Command {
+    Serve,
+    Export(Vec<String>),
+    Import(bool),
+}
+
+/// Take the command and its flags out of the arguments.
+fn parse_command(arg_v: &mut Vec<String>) -> Command {
+    match arg_v.first().map(|s| s.as_str()) {
+        Some("export") => {
+            arg_v.remove(0);
+            let mut code_v = Vec::new();
+            if let Some(i) = arg_v.iter().position(|arg| arg == "--codes") {
+                arg_v.remove(i);
+                if i < arg_v.len() {
+                    code_v = arg_v.remove(i).split(',').map(|s| s.to_string()).collect();
+                }
+            }
+            Command::Export(code_v)
+        }
+        Some("import") => {
+            arg_v.remove(0);
+            let replace = match arg_v.iter().position(|arg| arg == "--replace") {
+                Some(i) => {
+                    arg_v.remove(i);
+                    true
+                }
+                None => false,
+            };
+            Command::Import(replace)
+        }
+        _ => Command::Serve,
+    }
+}
+
+async fn export_data(data_dir: &str, code_v: &[String]) -> io::Result<()> {
+    if data_dir == "_" {
+        return Err(io::Error::other("data_dir is not set\nwhen export_data"));
+    }
+    let mut global = MemDataManager::new(None);
+    store::Store::open(data_dir, &mut global).await?;
+    let export = export::export(&mut global, code_v)
+        .await
+        .map_err(|e| io::Error::other(format!("{e}\nwhen export_data")))?;
+    let content = serde_json::to_string_pretty(&export)
+        .map_err(|e| io::Error::other(format!("{e}\nwhen export_data")))?;
+    println!("{content}");
+    Ok(())
+}
+
+async fn import_data(data_dir: &str, replace: bool) -> io::Result<()> {
+    if data_dir == "_" {
+        return Err(io::Error::other("data_dir is not set\nwhen import_data"));
+    }
+    let mut content = String::new();
+    io::stdin().read_to_string(&mut content)?;
+    let export = serde_json::from_str::<export::Export>(&content)
+        .map_err(|e| io::Error::other(format!("{e}\nwhen import_data")))?;
+    let mut global = MemDataManager::new(None);
+    let store = store::Store::open(data_dir, &mut global).await?;
+    export::import(&mut global, &export, replace)
+        .await
+        .map_err(|e| io::Error::other(format!("{e}\nwhen import_data")))?;
+    store.append(&export::import_script(&export, replace))?;
+    store.snapshot(&mut global).await
+}
+
+/// Execute a script of the config, audited as such.
+async fn config_execute(
+    global: &mut MemDataManager,
+    script: &[String],
+) -> err::Result<Vec<String>> {
+    audit::scope(
+        audit::Actor::internal("config"),
+        transaction::execute(global, script),
+    )
+    .await
+}
@@ -0,0 +1,174 @@
+//! Pick the addresses of this host to advertise to moon servers.
+use std::net::IpAddr;
+
+use pnet::datalink;
+
+// Public
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Family {
+    Ipv4,
+    Ipv6,
+    Any,
+}
+
+impl Family {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "ipv4" => Some(Self::Ipv4),
+            "ipv6" => Some(Self::Ipv6),
+            "any" => Some(Self::Any),
+            _ => None,
+        }
+    }
+
+    fn contains(&self, ip: &IpAddr) -> bool {
+        match self {
+            Self::Ipv4 => ip.is_ipv4(),
+            Self::Ipv6 => ip.is_ipv6(),
+            Self::Any => true,
+        }
+    }
+}
+
+/// Where an address can be reached from.
+///
+/// - `global`: anywhere.
+/// - `ula`: the site, an IPv6 unique local address in `fc00::/7`.
+/// - `private`: the site, an IPv4 address in the ranges of RFC 1918.
+/// - `link`: the link, `fe80::/10` or `169.254.0.0/16`.
+/// - `loopback`: this host.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Scope {
+    Global,
+    Ula,
+    Private,
+    Link,
+    Loopback,
+}
+
+impl Scope {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "global" => Some(Self::Global),
+            "ula" => Some(Self::Ula),
+            "private" => Some(Self::Private),
+            "link" => Some(Self::Link),
+            "loopback" => Some(Self::Loopback),
+            _ => None,
+        }
+    }
+
+    /// Classify the address, `None` for those that can't be advertised, like multicast.
+    pub fn of(ip: &IpAddr) -> Option<Self> {
+        if ip.is_unspecified() || ip.is_multicast() {
+            return None;
+        }
+        if ip.is_loopback() {
+            return Some(Self::Loopback);
+        }
+        match ip {
+            IpAddr::V4(ip) => {
+                if ip.is_broadcast() || ip.is_documentation() {
+                    None
+                } else if ip.is_link_local() {
+                    Some(Self::Link)
+                } else if ip.is_private() {
+                    Some(Self::Private)
+                } else {
+                    Some(Self::Global)
+                }
+            }
+            IpAddr::V6(ip) => {
+                let first = ip.segments()[0];
+                if first & 0xffc0 == 0xfe80 {
+                    Some(Self::Link)
+                } else if first & 0xfe00 == 0xfc00 {
+                    Some(Self::Ula)
+                } else if first == 0x2001 && ip.segments()[1] == 0x0db8 {
+                    // Documentation
+                    None
+                } else if first & 0xe000 == 0x2000 {
+                    Some(Self::Global)
+                } else {
+                    // Reserved, deprecated site-local or mapped addresses.
+                    None
+                }
+            }
+        }
+    }
+}
+
+/// Which addresses of this host are eligible.
+#[derive(Clone, Debug)]
+pub struct Filter {
+    /// Name of the interface, any if `None`.
+    pub interface: Option<String>,
+    pub family: Family,
+    pub scope_v: Vec<Scope>,
+}
+
+impl Filter {
+    pub fn allow(&self, interface: &str, ip: &IpAddr) -> bool {
+        self.interface
+            .as_deref()
+            .is_none_or(|name| name == interface)
+            && self.family.contains(ip)
+            && Scope::of(ip).is_some_and(|scope| self.scope_v.contains(&scope))
+    }
+
+    /// Every eligible address of the interfaces that are up.
+    pub fn list(&self) -> Vec<IpAddr> {
+        let mut ip_v = Vec::new();
+        for interface in datalink::interfaces() {
+            if !interface.is_up() {
+                continue;
+            }
+            for ip in &interface.ips {
+                let ip = ip.ip();
+                if self.allow(&interface.name, &ip) && !ip_v.contains(&ip) {
+                    ip_v.push(ip);
+                }
+            }
+        }
+        ip_v
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+
+    use super::{Family, Filter, Scope};
+
+    fn scope(s: &str) -> Option<Scope> {
+        Scope::of(&s.parse::<IpAddr>().unwrap())
+    }
+
+    #[test]
+    fn test_scope() {
+        assert_eq!(scope("2409:8a00::1"), Some(Scope::Global));
+        assert_eq!(scope("fd12:3456::1"), Some(Scope::Ula));
+        assert_eq!(scope("fe80::1"), Some(Scope::Link));
+        assert_eq!(scope("::1"), Some(Scope::Loopback));
+        assert_eq!(scope("2001:db8::1"), None);
+        assert_eq!(scope("ff02::1"), None);
+        assert_eq!(scope("8.8.8.8"), Some(Scope::Global));
+        assert_eq!(scope("192.168.1.2"), Some(Scope::Private));
+        assert_eq!(scope("169.254.0.1"), Some(Scope::Link));
+        assert_eq!(scope("127.0.0.1"), Some(Scope::Loopback));
+        assert_eq!(scope("0.0.0.0"), None);
+    }
+
+    #[test]
+    fn test_filter() {
+        let filter = Filter {
+            interface: Some("eth0".to_string()),
+            family: Family::Ipv4,
+            scope_v: vec![Scope::Global, Scope::Private],
+        };
+        assert!(filter.allow("eth0", &"10.0.0.2".parse().unwrap()));
+        assert!(!filter.allow("eth1", &"10.0.0.2".parse().unwrap()));
+        assert!(!filter.allow("eth0", &"2409:8a00::1".parse().unwrap()));
+        assert!(!filter.allow("eth0", &"169.254.0.1".parse().unwrap()));
+    }
+}
@@ -0,0 +1,194 @@
+//! Audit log of the scripts that change the graph, who ran them, from where and when.
+//!
+//! Scripts are recorded on behalf of the actor of the task running them: requests are scoped by
+//! the middleware with their source address and named by their caller once authenticated,
+//! background components are scoped by name where they are spawned. The latest entries are kept
+//! in memory for `{path}/admin/audit`, and all of them are appended to `audit_log` if it is set.
+use std::{
+    cell::RefCell,
+    collections::VecDeque,
+    fs::{File, OpenOptions},
+    future::Future,
+    io::{self, Write},
+    os::unix::fs::OpenOptionsExt,
+    sync::{Mutex, OnceLock},
+};
+
+use crate::util::{registry, server::auth};
+
+// Public
+/// Who scripts are run on behalf of.
+#[derive(Clone, Debug)]
+pub struct Actor {
+    /// anonymous, admin:{id}, service:{name} or internal:{component}.
+    pub caller: String,
+    pub source: Option<String>,
+}
+
+impl Actor {
+    /// A request from `source`, anonymous until it is authenticated.
+    pub fn request(source: Option<String>) -> Self {
+        Self {
+            caller: "anonymous".to_string(),
+            source,
+        }
+    }
+
+    pub fn internal(component: &str) -> Self {
+        Self {
+            caller: format!("internal:{component}"),
+            source: None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+pub struct Entry {
+    /// Seconds since the unix epoch.
+    pub time: u64,
+    pub caller: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source: Option<String>,
+    pub script: Vec<String>,
+}
+
+/// Keep the latest `size` entries in memory, and append all entries to the file at `path`,
+/// created readable by its owner only.
+///
+/// Called once on start, before anything is recorded.
+pub fn init(path: Option<&str>, size: usize) -> io::Result<()> {
+    let file = match path {
+        Some(path) => Some(
+            OpenOptions::new()
+                .create(true)
+                .append(true)
+                .mode(0o600)
+                .open(path)
+                .map_err(|e| io::Error::other(format!("{e}\nwhen init {path}")))?,
+        ),
+        None => None,
+    };
+    if AUDIT.set(Audit::new(file, size)).is_err() {
+        log::warn!("audit log is initialized already\nwhen init");
+    }
+    Ok(())
+}
+
+/// Run `future` on behalf of `actor`.
+pub async fn scope<F: Future>(actor: Actor, future: F) -> F::Output {
+    ACTOR.scope(RefCell::new(actor), future).await
+}
+
+/// Name the caller of the current request, once it is authenticated.
+pub fn identify(caller: String) {
+    let _ = ACTOR.try_with(|actor| actor.borrow_mut().caller = caller);
+}
+
+/// Record a script executed on the graph on behalf of the current actor, scripts that write
+/// nothing are skipped.
+pub fn record(script: &[String]) {
+    if auth::check_script(&[auth::Scope::Read], script).is_ok() {
+        return;
+    }
+    let actor = ACTOR
+        .try_with(|actor| actor.borrow().clone())
+        .unwrap_or_else(|_| Actor::internal("unknown"));
+    get().push(Entry {
+        time: registry::now(),
+        caller: actor.caller,
+        source: actor.source,
+        script: script.to_vec(),
+    });
+}
+
+/// Latest entries, newest first, of `caller` if given and not older than `since` if given.
+pub fn recent(limit: usize, caller: Option<&str>, since: Option<u64>) -> Vec<Entry> {
+    get()
+        .entry_v
+        .lock()
+        .unwrap()
+        .iter()
+        .rev()
+        .filter(|entry| caller.map(|caller| entry.caller == caller).unwrap_or(true))
+        .filter(|entry| since.map(|since| entry.time >= since).unwrap_or(true))
+        .take(limit)
+        .cloned()
+        .collect()
+}
+
+// Private
+const DEFAULT_SIZE: usize = 1000;
+
+static AUDIT: OnceLock<Audit> = OnceLock::new();
+
+tokio::task_local! {
+    static ACTOR: RefCell<Actor>;
+}
+
+struct Audit {
+    size: usize,
+    entry_v: Mutex<VecDeque<Entry>>,
+    file: Option<Mutex<File>>,
+}
+
+impl Audit {
+    fn new(file: Option<File>, size: usize) -> Self {
+        Self {
+            size: size.max(1),
+            entry_v: Mutex::new(VecDeque::new()),
+            file: file.map(Mutex::new),
+        }
+    }
+
+    fn push(&self, entry: Entry) {
+        if let Some(file) = &self.file {
+            match serde_json::to_string(&entry) {
+                Ok(line) => {
+                    if let Err(e) = file
+                        .lock()
+                        .unwrap()
+                        .write_all(format!("{line}\n").as_bytes())
+                    {
+                        log::error!("{e}\nwhen push");
+                    }
+                }
+                Err(e) => log::error!("{e}\nwhen push"),
+            }
+        }
+        let mut entry_v = self.entry_v.lock().unwrap();
+        if entry_v.len() >= self.size {
+            entry_v.pop_front();
+        }
+        entry_v.push_back(entry);
+    }
+}
+
+fn get() -> &'static Audit {
+    AUDIT.get_or_init(|| Audit::new(None, DEFAULT_SIZE))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Audit, Entry};
+
+    #[test]
+    fn test_push() {
+        let audit = Audit::new(None, 2);
+        for time in 0..3 {
+            audit.push(Entry {
+                time,
+                caller: "admin:ops".to_string(),
+                source: None,
+                script: vec!["root->lease = 60 _".to_string()],
+            });
+        }
+        let time_v = audit
+            .entry_v
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|entry| entry.time)
+            .collect::<Vec<u64>>();
+        assert_eq!(time_v, vec![1, 2]);
+    }
+}
@@ -1,4 +1,8 @@
-use std::{io, sync::Arc, time::Duration};
+use std::{
+    io,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
 use edge_lib::util::{
     data::{AsDataManager, MemDataManager},
@@ -7,9 +11,7 @@ use edge_lib::util::{
 };
 use tokio::{sync::Mutex, time};
 
-use crate::util;
-
-const SLEEP_TIME: Duration = Duration::from_secs(10);
+use crate::util::{self, address, registry, sign};
 
 pub struct HttpConnector {
     global: Arc<Mutex<MemDataManager>>,
@@ -26,64 +28,369 @@ impl HttpConnector {
                 log::warn!("{e}\nwhen run");
             }
 
-            time::sleep(SLEEP_TIME).await;
+            time::sleep(self.sleep_time().await).await;
         }
     }
 
+    /// Remove this server from every moon server.
+    pub async fn deregister(&self) -> io::Result<()> {
+        let registration = match self.registration().await? {
+            Some(registration) => registration,
+            None => return Ok(()),
+        };
+        let call_v = registration
+            .web_server_v
+            .iter()
+            .map(|web_server| {
+                let body = serde_json::json!({
+                    "name": web_server.name,
+                    "ip": web_server.ip,
+                    "port": web_server.port,
+                })
+                .to_string();
+                let script =
+                    registry::remove_script(&web_server.name, &web_server.ip, &web_server.port);
+                (body, script)
+            })
+            .collect::<Vec<(String, Vec<String>)>>();
+        for uri in &registration.moon_server_v {
+            log::info!("deregistering from {uri}");
+            for (body, script) in &call_v {
+                if let Err(e) = util::native::http_registry_or_script(
+                    uri,
+                    "/registry/deregister",
+                    Some(body.clone()),
+                    script,
+                    registration.credential.as_ref(),
+                )
+                .await
+                {
+                    log::warn!("{e}\nwhen deregister");
+                } else {
+                    log::info!("deregistered from {uri}");
+                }
+            }
+        }
+        Ok(())
+    }
+
     async fn execute(&self) -> io::Result<()> {
-        let mut global = self.global.lock().await;
-        let domain_v = global
-            .get(&Path::from_str("root->domain"))
-            .await
-            .map_err(|e| io::Error::other(format!("{e:?}\nwhen execute")))?;
-        let ip = if domain_v.is_empty() {
-            util::native::get_global_ipv6()
-                .map_err(|e| io::Error::other(format!("{e}\nwhen execute")))?
-        } else {
-            domain_v[0].clone()
+        let registration = match self.registration().await? {
+            Some(registration) => registration,
+            None => return Ok(()),
         };
+        // Renewed on each heartbeat. Moon servers count the lease on their own clock and evict
+        // the server once it expires. Those without the registry API get the expiry by this
+        // light's clock in a script.
+        let expire = registry::now() + registration.lease;
+        let mut call_v = Vec::new();
+        for web_server in &registration.web_server_v {
+            let mut body = serde_json::to_value(web_server)
+                .map_err(|e| io::Error::other(format!("{e}\nwhen execute")))?;
+            body["lease"] = registration.lease.into();
+            let mut web_server = web_server.clone();
+            web_server.expire = Some(expire);
+            call_v.push((body.to_string(), registry::register_script(&web_server)));
+        }
+        for uri in &registration.moon_server_v {
+            log::info!("reporting to {uri}");
+            for (body, script) in &call_v {
+                if let Err(e) = util::native::http_registry_or_script(
+                    uri,
+                    "/registry/register",
+                    Some(body.clone()),
+                    script,
+                    registration.credential.as_ref(),
+                )
+                .await
+                {
+                    log::warn!("{e}\nwhen execute");
+                } else {
+                    log::info!("reported to {uri}");
+                }
+            }
+        }
+        Ok(())
+    }
 
-        let mut edge_engine = EdgeEngine::new(&mut *global);
-        let rs = edge_engine
-            .execute_script(&[
-                format!("$->$:output = root->name _"),
-                format!("$->$:output append $->$:output root->port"),
-                format!("$->$:output append $->$:output root->path"),
-            ])
+    /// Get what is reported to moon servers, `None` if there is no moon server.
+    async fn registration(&self) -> io::Result<Option<Registration>> {
+        let mut global = self.global.lock().await;
+        let moon_server_v = global
+            .get(&Path::from_str("root->moon_server"))
             .await
-            .map_err(|e| io::Error::other(format!("{e:?}\nwhen execute")))?;
+            .map_err(|e| io::Error::other(format!("{e:?}\nwhen registration")))?;
+        if moon_server_v.is_empty() {
+            // Running standalone, there is nobody to report to.
+            return Ok(None);
+        }
+        let web_server_v = get_web_servers(&mut *global).await?;
+        let lease = registry::get_lease(&mut *global).await;
+        let credential = sign::get_credential();
+
+        Ok(Some(Registration {
+            moon_server_v,
+            web_server_v,
+            lease,
+            credential,
+        }))
+    }
+
+    /// Heartbeat interval plus a random jitter, so that servers don't report at once.
+    async fn sleep_time(&self) -> Duration {
+        let mut global = self.global.lock().await;
+        let mut value_v = Vec::new();
+        for code in ["heartbeat", "heartbeat_jitter"] {
+            value_v.push(
+                global
+                    .get(&Path::from_str(&format!("root->{code}")))
+                    .await
+                    .ok()
+                    .and_then(|value_v| value_v.first().and_then(|s| s.parse::<u64>().ok())),
+            );
+        }
+        let heartbeat = value_v[0].unwrap_or(DEFAULT_HEARTBEAT) * 1000;
+        let jitter = value_v[1].unwrap_or(0) * 1000;
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.subsec_nanos() as u64)
+            .unwrap_or(0);
+        Duration::from_millis(heartbeat + nanos % (jitter + 1))
+    }
+}
+
+/// Subscribes to changes of the registry on moon servers and applies them to the local cache,
+/// so routing converges within seconds of a deploy.
+pub struct HttpWatcher {
+    global: Arc<Mutex<MemDataManager>>,
+}
+
+impl HttpWatcher {
+    pub fn new(global: Arc<Mutex<MemDataManager>>) -> Self {
+        Self { global }
+    }
 
-        let name = &rs[0];
-        let port = &rs[1];
-        let path = &rs[2];
+    pub async fn run(self) -> io::Result<()> {
+        let client = reqwest::Client::builder()
+            .timeout(WATCH_TIMEOUT + Duration::from_secs(10))
+            .build()
+            .map_err(|e| io::Error::other(format!("{e}\nwhen run")))?;
+        let mut version = String::new();
+        // Web servers of the snapshot merged last, those leaving the registry get removed.
+        let mut web_server_v = Vec::new();
+        let mut i = 0;
+        loop {
+            let moon_server_v = self
+                .global
+                .lock()
+                .await
+                .get(&Path::from_str("root->moon_server"))
+                .await
+                .map_err(|e| io::Error::other(format!("{e:?}\nwhen run")))?;
+            if moon_server_v.is_empty() {
+                time::sleep(Duration::from_secs(DEFAULT_HEARTBEAT)).await;
+                continue;
+            }
+            let uri = &moon_server_v[i % moon_server_v.len()];
+            match self.execute(&client, uri, &version, &web_server_v).await {
+                Ok(Some(snapshot)) => {
+                    log::info!("registry of {uri} changed to {}", snapshot.version);
+                    version = snapshot.version;
+                    web_server_v = snapshot.web_servers;
+                }
+                Ok(None) => (),
+                Err(e) => {
+                    // Try the next moon server, it may be down or not serve the registry.
+                    log::warn!("{e}\nwhen run");
+                    version.clear();
+                    i += 1;
+                    time::sleep(Duration::from_secs(DEFAULT_HEARTBEAT)).await;
+                }
+            }
+        }
+    }
 
-        let moon_server_v = edge_engine
-            .execute_script(&[format!("$->$:output = root->moon_server _")])
+    /// Wait for a change and merge it over `previous`, get the new snapshot if there is one.
+    async fn execute(
+        &self,
+        client: &reqwest::Client,
+        uri: &str,
+        version: &str,
+        previous: &[registry::WebServer],
+    ) -> io::Result<Option<registry::Snapshot>> {
+        // Built in full first, the query is signed too.
+        let url = reqwest::Url::parse_with_params(
+            &format!("{uri}/registry/watch"),
+            &[
+                ("version", version.to_string()),
+                ("timeout", WATCH_TIMEOUT.as_secs().to_string()),
+            ],
+        )
+        .map_err(|e| io::Error::other(format!("{e}\nwhen execute")))?;
+        let mut builder = client.get(url.clone());
+        if let Some(credential) = sign::get_credential() {
+            builder = builder.header(
+                "Authorization",
+                credential
+                    .authorization("GET", url.as_str(), &[])
+                    .map_err(|e| io::Error::other(format!("{e}\nwhen execute")))?,
+            );
+        }
+        let res = builder
+            .send()
             .await
-            .map_err(|e| io::Error::other(format!("{e:?}\nwhen execute")))?;
-        drop(edge_engine);
-        drop(global);
-
-        let script = vec![
-            format!("$->$:server_exists inner root->web_server {name}<-name"),
-            format!("$->$:web_server if $->$:server_exists ?"),
-            format!("$->$:web_server->name = {name} _"),
-            format!("$->$:web_server->ip = {ip} _"),
-            format!("$->$:web_server->port = {port} _"),
-            format!("$->$:web_server->path = {path} _"),
-            format!("$->$:web_server left $->$:web_server $->$:server_exists"),
-            format!("root->web_server append root->web_server $->$:web_server"),
-        ];
-        for uri in &moon_server_v {
-            log::info!("reporting to {uri}");
-            if let Err(e) = util::native::http_execute_script(&uri, &script).await {
-                log::warn!("{e}\nwhen execute");
-            } else {
-                log::info!("reported to {uri}");
+            .map_err(|e| io::Error::other(format!("{e}\nwhen execute")))?;
+        match res.status() {
+            reqwest::StatusCode::OK => (),
+            reqwest::StatusCode::NO_CONTENT => return Ok(None),
+            status => {
+                return Err(io::Error::other(format!(
+                    "{uri} responded {status}\nwhen execute"
+                )))
             }
         }
-        Ok(())
+        let snapshot = serde_json::from_str::<registry::Snapshot>(
+            &res.text()
+                .await
+                .map_err(|e| io::Error::other(format!("{e}\nwhen execute")))?,
+        )
+        .map_err(|e| io::Error::other(format!("{e}\nwhen execute")))?;
+        registry::apply(&mut *self.global.lock().await, previous, &snapshot)
+            .await
+            .map_err(|e| io::Error::other(format!("{e}\nwhen execute")))?;
+        Ok(Some(snapshot))
+    }
+}
+
+/// Web servers of this light, one for each advertised address.
+pub async fn get_web_servers(global: &mut MemDataManager) -> io::Result<Vec<registry::WebServer>> {
+    let domain_v = global
+        .get(&Path::from_str("root->domain"))
+        .await
+        .map_err(|e| io::Error::other(format!("{e:?}\nwhen get_web_servers")))?;
+    let scheme_v = global
+        .get(&Path::from_str("root->scheme"))
+        .await
+        .map_err(|e| io::Error::other(format!("{e:?}\nwhen get_web_servers")))?;
+    let ip_v = if scheme_v.first().map(|s| s.as_str()) == Some("unix") {
+        // The socket path is the address.
+        global
+            .get(&Path::from_str("root->ip"))
+            .await
+            .map_err(|e| io::Error::other(format!("{e:?}\nwhen get_web_servers")))?
+    } else if domain_v.is_empty() {
+        get_address_filter(&mut *global)
+            .await?
+            .list()
+            .iter()
+            .map(|ip| ip.to_string())
+            .collect()
+    } else {
+        domain_v[0]
+            .split(',')
+            .map(|s| s.trim().to_string())
+            .filter(|s| !s.is_empty())
+            .collect()
+    };
+    if ip_v.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            "no eligible address\nwhen get_web_servers",
+        ));
+    }
+
+    let sni = global
+        .get(&Path::from_str("root->tls_sni"))
+        .await
+        .map_err(|e| io::Error::other(format!("{e:?}\nwhen get_web_servers")))?
+        .first()
+        .cloned();
+    let meta: std::collections::BTreeMap<String, String> = global
+        .get(&Path::from_str("root->meta"))
+        .await
+        .map_err(|e| io::Error::other(format!("{e:?}\nwhen get_web_servers")))?
+        .iter()
+        .filter_map(|meta| meta.split_once('='))
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .collect();
+    let tags = global
+        .get(&Path::from_str("root->tag"))
+        .await
+        .map_err(|e| io::Error::other(format!("{e:?}\nwhen get_web_servers")))?;
+
+    let mut edge_engine = EdgeEngine::new(&mut *global);
+    let rs = edge_engine
+        .execute_script(&[
+            format!("$->$:output = root->name _"),
+            format!("$->$:output append $->$:output root->port"),
+            format!("$->$:output append $->$:output root->path"),
+            format!("$->$:output append $->$:output root->scheme"),
+        ])
+        .await
+        .map_err(|e| io::Error::other(format!("{e:?}\nwhen get_web_servers")))?;
+
+    // Each address is reported as an instance, so clients can choose what they reach.
+    Ok(ip_v
+        .into_iter()
+        .map(|ip| registry::WebServer {
+            name: rs[0].clone(),
+            scheme: rs[3].clone(),
+            ip,
+            port: rs[1].clone(),
+            path: rs[2].clone(),
+            sni: sni.clone(),
+            expire: None,
+            meta: meta.clone(),
+            tags: tags.clone(),
+        })
+        .collect())
+}
+
+// Private
+const WATCH_TIMEOUT: Duration = Duration::from_secs(30);
+
+const DEFAULT_HEARTBEAT: u64 = 10;
+
+struct Registration {
+    moon_server_v: Vec<String>,
+    /// One for each advertised address.
+    web_server_v: Vec<registry::WebServer>,
+    /// Seconds a registration stays valid without a heartbeat.
+    lease: u64,
+    credential: Option<sign::Credential>,
+}
+
+/// Which addresses to advertise, from `root->interface`, `root->family` and `root->scope`.
+async fn get_address_filter(global: &mut MemDataManager) -> io::Result<address::Filter> {
+    let mut value_v_v = Vec::new();
+    for code in ["interface", "family", "scope"] {
+        value_v_v.push(
+            global
+                .get(&Path::from_str(&format!("root->{code}")))
+                .await
+                .map_err(|e| io::Error::other(format!("{e:?}\nwhen get_address_filter")))?,
+        );
     }
+    let family = match value_v_v[1].first() {
+        Some(s) => address::Family::parse(s).ok_or(io::Error::other(format!(
+            "invalid family: {s}\nwhen get_address_filter"
+        )))?,
+        None => address::Family::Ipv6,
+    };
+    let mut scope_v = Vec::new();
+    for s in &value_v_v[2] {
+        scope_v.push(address::Scope::parse(s).ok_or(io::Error::other(format!(
+            "invalid scope: {s}\nwhen get_address_filter"
+        )))?);
+    }
+    if scope_v.is_empty() {
+        scope_v.push(address::Scope::Global);
+    }
+    Ok(address::Filter {
+        interface: value_v_v[0].first().cloned(),
+        family,
+        scope_v,
+    })
 }
 
 #[cfg(test)]
@@ -94,6 +401,8 @@ mod tests {
         Path,
     };
 
+    use crate::util::registry;
+
     #[test]
     fn test() {
         tokio::runtime::Builder::new_multi_thread()
@@ -133,4 +442,38 @@ mod tests {
                 assert!(!rs.is_empty());
             })
     }
+
+    #[test]
+    fn test_register_script() {
+        tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(4)
+            .enable_all()
+            .build()
+            .unwrap()
+            .block_on(async {
+                let mut global = MemDataManager::new(None);
+
+                let mut edge_engine = EdgeEngine::new(&mut global);
+                let web_server = registry::WebServer {
+                    name: "test".to_string(),
+                    scheme: "http".to_string(),
+                    ip: "0.0.0.0".to_string(),
+                    port: "8080".to_string(),
+                    path: "/test".to_string(),
+                    ..Default::default()
+                };
+
+                edge_engine
+                    .execute_script(&registry::register_script(&web_server))
+                    .await
+                    .unwrap();
+                drop(edge_engine);
+
+                let rs = global
+                    .get(&Path::from_str("root->web_server"))
+                    .await
+                    .unwrap();
+                assert!(!rs.is_empty());
+            })
+    }
 }
@@ -0,0 +1,197 @@
+//! Export of the global graph as JSON, and import of such an export.
+//!
+//! The graph can't list the attributes of its nodes, so an export holds what light keeps in it:
+//! routes, mounts, moon servers and web servers as the admin API lists them, and other attributes
+//! of `root` by code, as their values. An import merges into the graph, routes and mounts
+//! replacing those at the same paths and web servers those at the same address, or replaces the
+//! sections the export holds. Other attributes are set to the values in the export either way.
+use std::collections::BTreeMap;
+
+use edge_lib::util::{
+    data::{AsDataManager, MemDataManager},
+    Path,
+};
+
+use crate::{
+    err,
+    util::{
+        registry::{self, check_value},
+        route, transaction,
+    },
+};
+
+// Public
+/// Codes of root exported when none are asked for.
+pub const SECTIONS: [&str; 4] = ["proxy", "mount", "moon_server", "web_server"];
+
+#[derive(Clone, Debug, Default, PartialEq, serde::Deserialize, serde::Serialize)]
+pub struct Export {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub proxy: Option<Vec<route::Route>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mount: Option<Vec<route::Mount>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub moon_server: Option<Vec<String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub web_server: Option<Vec<registry::WebServer>>,
+    /// Other attributes of root by code, like `meta` or `lease`.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub root: BTreeMap<String, Vec<String>>,
+}
+
+impl Export {
+    /// Codes of root the export holds.
+    pub fn codes(&self) -> Vec<String> {
+        let mut code_v = Vec::new();
+        for (code, held) in [
+            ("proxy", self.proxy.is_some()),
+            ("mount", self.mount.is_some()),
+            ("moon_server", self.moon_server.is_some()),
+            ("web_server", self.web_server.is_some()),
+        ] {
+            if held {
+                code_v.push(code.to_string());
+            }
+        }
+        code_v.extend(self.root.keys().cloned());
+        code_v
+    }
+}
+
+/// Export the attributes `code_v` of root, [`SECTIONS`] if empty.
+pub async fn export(global: &mut MemDataManager, code_v: &[String]) -> err::Result<Export> {
+    let code_v = if code_v.is_empty() {
+        SECTIONS.map(|code| code.to_string()).to_vec()
+    } else {
+        code_v.to_vec()
+    };
+    let mut export = Export::default();
+    for code in &code_v {
+        check_code(code)?;
+        match code.as_str() {
+            "proxy" => export.proxy = Some(route::list_routes(global).await?),
+            "mount" => export.mount = Some(route::list_mounts(global).await?),
+            "moon_server" => export.moon_server = Some(route::list_moon_servers(global).await?),
+            "web_server" => export.web_server = Some(registry::lookup(global, None).await?),
+            _ => {
+                let value_v = global
+                    .get(&Path::from_str(&format!("root->{code}")))
+                    .await
+                    .map_err(|e| err::Error::Other(e.message().to_string()))?;
+                export.root.insert(code.clone(), value_v);
+            }
+        }
+    }
+    Ok(export)
+}
+
+/// `root_v` are the directories mounts may serve from, see [`route::get_mount_roots`].
+pub fn check_export(export: &Export, root_v: &[String]) -> err::Result<()> {
+    for route in export.proxy.iter().flatten() {
+        route::check_route(route)?;
+    }
+    for mount in export.mount.iter().flatten() {
+        route::check_mount(mount, root_v)?;
+    }
+    for uri in export.moon_server.iter().flatten() {
+        route::check_moon_server(uri)?;
+    }
+    for web_server in export.web_server.iter().flatten() {
+        registry::check_web_server(web_server)?;
+    }
+    for (code, value_v) in &export.root {
+        check_code(code)?;
+        if SECTIONS.contains(&code.as_str()) {
+            return Err(err::Error::Other(format!("{code} must not be in root")));
+        }
+        for value in value_v {
+            check_value(code, value)?;
+        }
+    }
+    Ok(())
+}
+
+/// Script to import the export, replacing the sections it holds if `replace`.
+pub fn import_script(export: &Export, replace: bool) -> Vec<String> {
+    let mut script = Vec::new();
+    for code in export.codes() {
+        if replace || export.root.contains_key(&code) {
+            script.push(format!("root->{code} = _ _"));
+        }
+    }
+    for route in export.proxy.iter().flatten() {
+        script.extend(route::route_script(route));
+    }
+    for mount in export.mount.iter().flatten() {
+        script.extend(route::mount_script(mount));
+    }
+    for uri in export.moon_server.iter().flatten() {
+        if !replace {
+            script.push(format!("root->moon_server left root->moon_server {uri}"));
+        }
+        script.push(format!("root->moon_server append root->moon_server {uri}"));
+    }
+    for web_server in export.web_server.iter().flatten() {
+        script.extend(registry::register_script(web_server));
+    }
+    for (code, value_v) in &export.root {
+        for value in value_v {
+            script.push(format!("root->{code} append root->{code} {value}"));
+        }
+    }
+    script
+}
+
+/// Import the export in one transaction, replacing the sections it holds if `replace`.
+pub async fn import(
+    global: &mut MemDataManager,
+    export: &Export,
+    replace: bool,
+) -> err::Result<()> {
+    check_export(export, &route::get_mount_roots(global).await)?;
+    transaction::execute(global, &import_script(export, replace)).await?;
+    Ok(())
+}
+
+// Private
+fn check_code(code: &str) -> err::Result<()> {
+    check_value("code", code)?;
+    if code.starts_with('$') {
+        return Err(err::Error::Other(format!("invalid code: {code:?}")));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{import_script, Export};
+
+    #[test]
+    fn test_import_script() {
+        let mut export = Export {
+            moon_server: Some(vec!["http://moon".to_string()]),
+            ..Default::default()
+        };
+        export
+            .root
+            .insert("lease".to_string(), vec!["60".to_string()]);
+        assert_eq!(
+            import_script(&export, false),
+            vec![
+                "root->lease = _ _",
+                "root->moon_server left root->moon_server http://moon",
+                "root->moon_server append root->moon_server http://moon",
+                "root->lease append root->lease 60",
+            ]
+        );
+        assert_eq!(
+            import_script(&export, true),
+            vec![
+                "root->moon_server = _ _",
+                "root->lease = _ _",
+                "root->moon_server append root->moon_server http://moon",
+                "root->lease append root->lease 60",
+            ]
+        );
+    }
+}
@@ -0,0 +1,523 @@
+//! Discover other lights by gossip, without moon servers.
+//!
+//! Each round a light bumps its heartbeat, renews its own web servers in `root->web_server` and
+//! exchanges a digest with a few members: the members it knows with their heartbeats and its own
+//! web servers. Both sides keep the newer of each member, so every light converges on the whole
+//! cluster, and each member gossips its web servers to the others in turn. A member whose
+//! heartbeat stops advancing for `FAIL_ROUNDS` rounds is dead and dropped, its web servers leave
+//! with their leases.
+//!
+//! Web servers are only taken from signed digests, and only those named as the service signing,
+//! so that a member can't take over the names of others. Members are only taken from signed
+//! digests and replies, replies being signed by the `moon_key` of the member replying, so that
+//! this light only calls, with its credential, lights vouched for by a key it accepts. At most
+//! `MAX_MEMBERS` are known.
+use std::{
+    collections::BTreeMap,
+    io,
+    sync::Arc,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use edge_lib::util::{
+    data::{AsDataManager, MemDataManager},
+    Path,
+};
+use tokio::{sync::Mutex, time};
+
+use crate::{
+    err,
+    util::{
+        connector, registry,
+        server::auth::{self, Caller},
+        sign, transaction,
+    },
+};
+
+// Public
+/// A light in the cluster, known by the uri its `path` is served at.
+#[derive(Clone, Debug, PartialEq, serde::Deserialize, serde::Serialize)]
+pub struct Member {
+    pub uri: String,
+    /// Bumped by the member each round.
+    pub heartbeat: u64,
+}
+
+/// What a light knows, sent to members and replied by them.
+#[derive(Clone, Debug, Default, serde::Deserialize, serde::Serialize)]
+pub struct Digest {
+    pub members: Vec<Member>,
+    pub web_servers: Vec<registry::WebServer>,
+}
+
+pub struct Cluster {
+    global: Arc<Mutex<MemDataManager>>,
+    state: std::sync::Mutex<State>,
+    /// Verifies replies.
+    verifier: sign::Verifier,
+}
+
+impl Cluster {
+    pub fn new(global: Arc<Mutex<MemDataManager>>) -> Self {
+        Self {
+            global,
+            state: std::sync::Mutex::new(State::default()),
+            verifier: sign::Verifier::new(),
+        }
+    }
+
+    pub async fn run(self: Arc<Self>) -> io::Result<()> {
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(5))
+            .build()
+            .map_err(|e| io::Error::other(format!("{e}\nwhen run")))?;
+        loop {
+            let interval = self.interval().await;
+            if let Err(e) = self.execute(&client, interval).await {
+                log::warn!("{e}\nwhen run");
+            }
+
+            time::sleep(interval).await;
+        }
+    }
+
+    /// Members this light knows, itself included, and its own web servers.
+    pub fn digest(&self) -> Digest {
+        let state = self.state.lock().unwrap();
+        let mut members = state
+            .member_mp
+            .iter()
+            .map(|(uri, peer)| Member {
+                uri: uri.clone(),
+                heartbeat: peer.heartbeat,
+            })
+            .collect::<Vec<Member>>();
+        if let Some(uri) = &state.uri {
+            members.push(Member {
+                uri: uri.clone(),
+                heartbeat: state.heartbeat,
+            });
+        }
+        Digest {
+            members,
+            web_servers: state.web_server_v.clone(),
+        }
+    }
+
+    /// Keep the newer of each member and web server in the digest of `caller`. Services may only
+    /// gossip the web servers named as them, admins those they may write, and unsigned callers
+    /// are only heard if `root->execute_open` is true, their members never.
+    pub async fn merge(&self, digest: Digest, caller: &Caller) -> err::Result<()> {
+        let mut global = self.global.lock().await;
+        let name = match caller {
+            Caller::Service(name) => Some(name.as_str()),
+            Caller::Admin(_, scope_v) if auth::allow_write(scope_v, "root->web_server") => None,
+            Caller::Anonymous if auth::is_open(&mut *global).await => None,
+            _ => {
+                return Err(err::Error::Other(format!(
+                    "{} may not gossip",
+                    caller.name()
+                )))
+            }
+        };
+        if !matches!(caller, Caller::Anonymous) {
+            self.merge_members(digest.members);
+        }
+
+        let known_mp = registry::lookup(&mut *global, None)
+            .await?
+            .into_iter()
+            .map(|web_server| (key(&web_server), web_server.expire))
+            .collect::<BTreeMap<(String, String, String), Option<u64>>>();
+        let now = registry::now();
+        // A lease can't outlast what this light would grant.
+        let max_expire = now + registry::get_lease(&mut *global).await;
+        let mut script = Vec::new();
+        for web_server in &digest.web_servers {
+            if name.map(|name| name != web_server.name) == Some(true) {
+                log::warn!(
+                    "{} may not gossip {}\nwhen merge",
+                    caller.name(),
+                    web_server.name
+                );
+                continue;
+            }
+            if let Err(e) = registry::check_web_server(web_server) {
+                log::warn!("{e}\nwhen merge");
+                continue;
+            }
+            let expire = match web_server.expire {
+                Some(expire) if expire >= now => expire.min(max_expire),
+                _ => continue,
+            };
+            match known_mp.get(&key(web_server)) {
+                // Local config without a lease is not overwritten.
+                Some(None) => continue,
+                Some(Some(known)) if *known >= expire => continue,
+                _ => script.extend(registry::register_script(&registry::WebServer {
+                    expire: Some(expire),
+                    ..web_server.clone()
+                })),
+            }
+        }
+        if !script.is_empty() {
+            transaction::execute(&mut *global, &script).await?;
+        }
+        Ok(())
+    }
+
+    /// Keep the newer of each member.
+    fn merge_members(&self, member_v: Vec<Member>) {
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+        for member in member_v {
+            if Some(&member.uri) == state.uri.as_ref() {
+                continue;
+            }
+            state.merge(member, now);
+        }
+    }
+
+    async fn execute(&self, client: &reqwest::Client, interval: Duration) -> io::Result<()> {
+        let (seed_v, credential, keys) = {
+            let mut global = self.global.lock().await;
+            let seed_v = global
+                .get(&Path::from_str("root->gossip_seed"))
+                .await
+                .map_err(|e| io::Error::other(format!("{e:?}\nwhen execute")))?;
+            let lease = registry::get_lease(&mut *global).await;
+            let mut web_server_v = connector::get_web_servers(&mut *global).await?;
+            let expire = registry::now() + lease;
+            let mut script = Vec::new();
+            for web_server in &mut web_server_v {
+                web_server.expire = Some(expire);
+                script.extend(registry::register_script(web_server));
+            }
+            transaction::execute(&mut *global, &script)
+                .await
+                .map_err(|e| io::Error::other(format!("{e}\nwhen execute")))?;
+
+            // Members reach this light at its first address, unix sockets can't be gossiped over.
+            let mut state = self.state.lock().unwrap();
+            state.uri = web_server_v
+                .iter()
+                .find(|web_server| web_server.scheme != "unix")
+                .map(|web_server| web_server.uri());
+            state.web_server_v = web_server_v;
+            drop(state);
+            let mut keys = sign::get_keys();
+            keys.extend(sign::get_admin_keys());
+            (seed_v, sign::get_credential(), keys)
+        };
+
+        let target_v = {
+            let mut state = self.state.lock().unwrap();
+            state.heartbeat += 1;
+            for uri in state.detect_failures(interval * FAIL_ROUNDS, Instant::now()) {
+                log::warn!("member {uri} failed");
+            }
+            state.targets(&seed_v)
+        };
+        let digest = self.digest();
+        let body = serde_json::to_string(&digest)
+            .map_err(|e| io::Error::other(format!("{e}\nwhen execute")))?;
+        for uri in &target_v {
+            let rs = match exchange(client, uri, &body, credential.as_ref()).await {
+                // Nothing to verify replies against, their members are ignored.
+                Ok(_) if keys.is_empty() => continue,
+                Ok((reply, authorization)) => {
+                    self.check_reply(uri, &keys, &reply, authorization.as_deref())
+                }
+                Err(e) => Err(e),
+            };
+            match rs {
+                Ok(reply) => self.merge_members(reply.members),
+                Err(e) => log::warn!("{e}\nwhen execute"),
+            }
+        }
+        Ok(())
+    }
+
+    /// Parse a reply of the member at `uri`, verifying it was signed by a key in `keys`.
+    fn check_reply(
+        &self,
+        uri: &str,
+        keys: &BTreeMap<String, String>,
+        reply: &str,
+        authorization: Option<&str>,
+    ) -> io::Result<Digest> {
+        let (htu, aud) = sign::split_url(&format!("{uri}/gossip"))
+            .map_err(|e| io::Error::other(format!("{e}\nwhen check_reply")))?;
+        self.verifier
+            .verify(
+                keys,
+                authorization,
+                ("POST", &htu),
+                &[aud],
+                reply.as_bytes(),
+            )
+            .map_err(|e| io::Error::other(format!("{e}\nwhen check_reply {uri}")))?;
+        serde_json::from_str(reply).map_err(|e| io::Error::other(format!("{e}\nwhen check_reply")))
+    }
+
+    /// Seconds between rounds, from `root->gossip_interval`.
+    async fn interval(&self) -> Duration {
+        let interval = self
+            .global
+            .lock()
+            .await
+            .get(&Path::from_str("root->gossip_interval"))
+            .await
+            .ok()
+            .and_then(|value_v| value_v.first().and_then(|s| s.parse::<u64>().ok()))
+            .unwrap_or(DEFAULT_INTERVAL);
+        Duration::from_secs(interval.max(1))
+    }
+}
+
+// Private
+const DEFAULT_INTERVAL: u64 = 1;
+
+/// Rounds without a new heartbeat before a member is dead.
+const FAIL_ROUNDS: u32 = 5;
+
+/// Members each round gossips with.
+const FANOUT: usize = 3;
+
+/// Members known at most, those joining beyond are ignored until others fail.
+const MAX_MEMBERS: usize = 64;
+
+#[derive(Default)]
+struct State {
+    /// Uri of this light, `None` until its web servers are known.
+    uri: Option<String>,
+    heartbeat: u64,
+    member_mp: BTreeMap<String, Peer>,
+    /// Web servers of this light, as renewed last.
+    web_server_v: Vec<registry::WebServer>,
+}
+
+impl State {
+    fn merge(&mut self, member: Member, now: Instant) {
+        match self.member_mp.get_mut(&member.uri) {
+            Some(peer) if peer.heartbeat >= member.heartbeat => (),
+            Some(peer) => {
+                peer.heartbeat = member.heartbeat;
+                peer.seen = now;
+            }
+            None if self.member_mp.len() >= MAX_MEMBERS => {
+                log::warn!("member {} ignored, {MAX_MEMBERS} known", member.uri);
+            }
+            None => {
+                log::info!("member {} joined", member.uri);
+                self.member_mp.insert(
+                    member.uri,
+                    Peer {
+                        heartbeat: member.heartbeat,
+                        seen: now,
+                    },
+                );
+            }
+        }
+    }
+
+    /// Drop members not heard of for `timeout`, get their uris.
+    fn detect_failures(&mut self, timeout: Duration, now: Instant) -> Vec<String> {
+        let failed_v = self
+            .member_mp
+            .iter()
+            .filter(|(_, peer)| now.duration_since(peer.seen) > timeout)
+            .map(|(uri, _)| uri.clone())
+            .collect::<Vec<String>>();
+        for uri in &failed_v {
+            self.member_mp.remove(uri);
+        }
+        failed_v
+    }
+
+    /// Up to `FANOUT` members or seeds at random, seeds keep a partitioned light coming back.
+    fn targets(&self, seed_v: &[String]) -> Vec<String> {
+        let mut uri_v = self.member_mp.keys().cloned().collect::<Vec<String>>();
+        for seed in seed_v {
+            if !uri_v.contains(seed) {
+                uri_v.push(seed.clone());
+            }
+        }
+        uri_v.retain(|uri| Some(uri) != self.uri.as_ref());
+        if uri_v.is_empty() {
+            return uri_v;
+        }
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.subsec_nanos() as usize)
+            .unwrap_or(0);
+        let len = uri_v.len();
+        uri_v.rotate_left(nanos % len);
+        uri_v.truncate(FANOUT);
+        uri_v
+    }
+}
+
+struct Peer {
+    heartbeat: u64,
+    /// When its heartbeat last advanced.
+    seen: Instant,
+}
+
+fn key(web_server: &registry::WebServer) -> (String, String, String) {
+    (
+        web_server.name.clone(),
+        web_server.ip.clone(),
+        web_server.port.clone(),
+    )
+}
+
+/// Send the digest to the member at `uri`, get its reply with its `Authorization`.
+async fn exchange(
+    client: &reqwest::Client,
+    uri: &str,
+    body: &str,
+    credential: Option<&sign::Credential>,
+) -> io::Result<(String, Option<String>)> {
+    let url = format!("{uri}/gossip");
+    let mut builder = client.post(&url).header("Content-Type", "application/json");
+    if let Some(credential) = credential {
+        builder = builder.header(
+            "Authorization",
+            credential
+                .authorization("POST", &url, body.as_bytes())
+                .map_err(|e| io::Error::other(format!("{e}\nwhen exchange")))?,
+        );
+    }
+    let res = builder
+        .body(body.to_string())
+        .send()
+        .await
+        .map_err(|e| io::Error::other(format!("{e}\nwhen exchange")))?;
+    let status = res.status();
+    let authorization = res
+        .headers()
+        .get(reqwest::header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .map(|s| s.to_string());
+    let text = res
+        .text()
+        .await
+        .map_err(|e| io::Error::other(format!("{e}\nwhen exchange")))?;
+    if !status.is_success() {
+        return Err(io::Error::other(format!(
+            "{uri} responded {status}: {text}\nwhen exchange"
+        )));
+    }
+    Ok((text, authorization))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::Arc,
+        time::{Duration, Instant},
+    };
+
+    use edge_lib::util::data::MemDataManager;
+    use tokio::sync::Mutex;
+
+    use super::{Caller, Cluster, Digest, Member, State, MAX_MEMBERS};
+    use crate::util::registry;
+
+    #[test]
+    fn test_merge() {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+            .block_on(async {
+                let global = Arc::new(Mutex::new(MemDataManager::new(None)));
+                let cluster = Cluster::new(global.clone());
+                let web_server = |name: &str, ip: &str| registry::WebServer {
+                    name: name.to_string(),
+                    ip: ip.to_string(),
+                    port: "80".to_string(),
+                    expire: Some(registry::now() + 3600),
+                    ..Default::default()
+                };
+                let digest = Digest {
+                    members: vec![Member {
+                        uri: "http://127.0.0.1:8081/light".to_string(),
+                        heartbeat: 1,
+                    }],
+                    web_servers: vec![
+                        web_server("api", "::1"),
+                        web_server("web", "::2"),
+                        web_server("api", "::3<-ip"),
+                    ],
+                };
+                // Unsigned gossip is not heard unless execute is open, its members never.
+                assert!(cluster
+                    .merge(digest.clone(), &Caller::Anonymous)
+                    .await
+                    .is_err());
+                assert!(cluster.digest().members.is_empty());
+
+                cluster
+                    .merge(digest, &Caller::Service("api".to_string()))
+                    .await
+                    .unwrap();
+                let web_server_v = registry::lookup(&mut *global.lock().await, None)
+                    .await
+                    .unwrap();
+                assert_eq!(web_server_v.len(), 1);
+                assert_eq!(web_server_v[0].ip, "::1");
+                assert_eq!(cluster.digest().members.len(), 1);
+                // Leased no longer than this light would.
+                assert!(
+                    web_server_v[0].expire.unwrap() <= registry::now() + registry::DEFAULT_LEASE
+                );
+            })
+    }
+
+    #[test]
+    fn test_membership() {
+        let mut state = State {
+            uri: Some("http://127.0.0.1:8080/light".to_string()),
+            ..Default::default()
+        };
+        let start = Instant::now();
+        let member = |heartbeat| Member {
+            uri: "http://127.0.0.1:8081/light".to_string(),
+            heartbeat,
+        };
+        state.merge(member(3), start);
+        // An older heartbeat doesn't count as alive.
+        state.merge(member(2), start + Duration::from_secs(4));
+        assert!(state
+            .detect_failures(Duration::from_secs(5), start + Duration::from_secs(4))
+            .is_empty());
+        state.merge(member(4), start + Duration::from_secs(4));
+        assert!(state
+            .detect_failures(Duration::from_secs(5), start + Duration::from_secs(8))
+            .is_empty());
+        assert_eq!(
+            state.detect_failures(Duration::from_secs(5), start + Duration::from_secs(10)),
+            vec!["http://127.0.0.1:8081/light".to_string()]
+        );
+
+        let target_v = state.targets(&[
+            "http://127.0.0.1:8080/light".to_string(),
+            "http://127.0.0.1:8082/light".to_string(),
+        ]);
+        assert_eq!(target_v, vec!["http://127.0.0.1:8082/light".to_string()]);
+
+        // Members beyond the cap are ignored.
+        for port in 0..MAX_MEMBERS + 1 {
+            state.merge(
+                Member {
+                    uri: format!("http://127.0.0.1:{}/light", 9000 + port),
+                    heartbeat: 1,
+                },
+                start,
+            );
+        }
+        assert_eq!(state.member_mp.len(), MAX_MEMBERS);
+    }
+}
@@ -1,46 +1,152 @@
 //! Let light be able to serve.
 
+pub mod audit;
 pub mod connector;
+pub mod export;
+pub mod gossip;
+pub mod registry;
+pub mod route;
 pub mod server;
+pub mod store;
+pub mod subscription;
+pub mod transaction;
+
+mod address;
+mod sign;
 
 mod native {
-    use pnet::datalink;
     use std::io;
 
-    pub fn get_global_ipv6() -> io::Result<String> {
-        let interfaces = datalink::interfaces();
-        for interface in &interfaces {
-            for ip in &interface.ips {
-                if ip.is_ipv6() {
-                    let ip_s = ip.ip().to_string();
-                    if !ip_s.starts_with("f") && !ip_s.starts_with(":") {
-                        return Ok(ip_s);
-                    }
-                }
+    /// Wait for SIGTERM or SIGINT.
+    pub async fn wait_for_stop_signal() -> io::Result<()> {
+        let mut terminate =
+            tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())?;
+        tokio::select! {
+            _ = terminate.recv() => log::info!("received SIGTERM"),
+            rs = tokio::signal::ctrl_c() => {
+                rs?;
+                log::info!("received SIGINT");
             }
         }
+        Ok(())
+    }
+
+    /// Call `{uri}{path}` of the registry API, posting `body` if given, getting otherwise. Signed
+    /// with `credential` if given.
+    pub async fn http_registry(
+        uri: &str,
+        path: &str,
+        body: Option<String>,
+        credential: Option<&super::sign::Credential>,
+    ) -> io::Result<String> {
+        let client = reqwest::Client::new();
+        let url = format!("{uri}{path}");
+        let (method, mut builder) = match &body {
+            Some(_) => (
+                "POST",
+                client.post(&url).header("Content-Type", "application/json"),
+            ),
+            None => ("GET", client.get(&url)),
+        };
+        if let Some(credential) = credential {
+            let signed = body.as_deref().unwrap_or("").as_bytes();
+            builder = builder.header(
+                "Authorization",
+                credential
+                    .authorization(method, &url, signed)
+                    .map_err(|e| io::Error::other(format!("{e}\nwhen http_registry")))?,
+            );
+        }
+        if let Some(body) = body {
+            builder = builder.body(body);
+        }
+        let res = builder
+            .send()
+            .await
+            .map_err(|e| io::Error::other(format!("{e}\nwhen http_registry")))?;
+        let status = res.status();
+        let text = res
+            .text()
+            .await
+            .map_err(|e| io::Error::other(format!("{e}\nwhen http_registry")))?;
+        if status == reqwest::StatusCode::NOT_FOUND {
+            // Moon servers serve the registry API only with `registry = true`.
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{uri}{path} responded {status}\nwhen http_registry"),
+            ));
+        }
+        if !status.is_success() {
+            return Err(io::Error::other(format!(
+                "{uri}{path} responded {status}: {text}\nwhen http_registry"
+            )));
+        }
+        Ok(text)
+    }
 
-        Err(io::Error::new(
-            io::ErrorKind::NotFound,
-            "Faild to get a global ipv6",
-        ))
+    /// What a moon server answered, by the registry API or by the script run in its place.
+    pub enum Answer {
+        Registry(String),
+        Script(Vec<String>),
     }
 
-    pub async fn http_execute_script(uri: &str, script: &[String]) -> io::Result<Vec<String>> {
-        let res = reqwest::Client::new()
-            .post(format!("{uri}/execute"))
-            .header("Content-Type", "application/json")
-            .body(serde_json::to_string(script).unwrap())
+    /// Call the registry API like [`http_registry`], executing `script` instead on moon servers
+    /// that don't serve it.
+    pub async fn http_registry_or_script(
+        uri: &str,
+        path: &str,
+        body: Option<String>,
+        script: &[String],
+        credential: Option<&super::sign::Credential>,
+    ) -> io::Result<Answer> {
+        match http_registry(uri, path, body, credential).await {
+            Ok(text) => Ok(Answer::Registry(text)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                log::debug!("{e}\nwhen http_registry_or_script, executing instead");
+                Ok(Answer::Script(
+                    http_execute_script(uri, script, credential).await?,
+                ))
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Execute the script on `uri`, signed with `credential` if given.
+    pub async fn http_execute_script(
+        uri: &str,
+        script: &[String],
+        credential: Option<&super::sign::Credential>,
+    ) -> io::Result<Vec<String>> {
+        let body = serde_json::to_string(script)
+            .map_err(|e| io::Error::other(format!("{e}\nwhen http_execute_script")))?;
+        let url = format!("{uri}/execute");
+        let mut builder = reqwest::Client::new()
+            .post(&url)
+            .header("Content-Type", "application/json");
+        if let Some(credential) = credential {
+            builder = builder.header(
+                "Authorization",
+                credential
+                    .authorization("POST", &url, body.as_bytes())
+                    .map_err(|e| io::Error::other(format!("{e}\nwhen http_execute_script")))?,
+            );
+        }
+        let res = builder
+            .body(body)
             .send()
             .await
-            .map_err(|e| {
-                log::error!("{e}");
-                io::Error::other(e)
-            })?;
-        Ok(serde_json::from_str(&res.text().await.map_err(|e| {
-            log::error!("{e}");
-            io::Error::other(e)
-        })?)
-        .unwrap())
+            .map_err(|e| io::Error::other(format!("{e}\nwhen http_execute_script")))?;
+        let status = res.status();
+        let text = res
+            .text()
+            .await
+            .map_err(|e| io::Error::other(format!("{e}\nwhen http_execute_script")))?;
+        if !status.is_success() {
+            return Err(io::Error::other(format!(
+                "{uri}/execute responded {status}: {text}\nwhen http_execute_script"
+            )));
+        }
+        serde_json::from_str(&text)
+            .map_err(|e| io::Error::other(format!("{e}\nwhen http_execute_script")))
     }
 }
@@ -0,0 +1,786 @@
+//! Registry of web servers in `root->web_server`.
+//!
+//! A light with `registry = true` serves the typed API over these functions, which
+//! [`crate::util::connector::HttpConnector`] reports to on moon servers.
+use std::{
+    collections::{hash_map::DefaultHasher, BTreeMap},
+    hash::{Hash, Hasher},
+    io,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use edge_lib::util::{
+    data::{AsDataManager, MemDataManager},
+    rs_2_str, Path,
+};
+use tokio::{
+    sync::{broadcast, Mutex},
+    time,
+};
+
+use crate::{
+    err,
+    util::{subscription, transaction},
+};
+
+const SLEEP_TIME: Duration = Duration::from_secs(5);
+
+// Public
+/// Seconds a registration stays valid without a renewal, if `root->lease` is not set.
+pub const DEFAULT_LEASE: u64 = 30;
+
+/// A web server in `root->web_server`.
+#[derive(Clone, Debug, Default, PartialEq, serde::Deserialize, serde::Serialize)]
+pub struct WebServer {
+    pub name: String,
+    /// http, https or unix. Default: http
+    #[serde(default = "default_scheme")]
+    pub scheme: String,
+    /// Address of the server, the socket path if the scheme is unix.
+    pub ip: String,
+    pub port: String,
+    #[serde(default)]
+    pub path: String,
+    /// Server name to verify and send in TLS, the uri is built on it if given.
+    #[serde(default)]
+    pub sni: Option<String>,
+    /// When the lease expires, in seconds since the unix epoch. `None` never expires.
+    #[serde(default)]
+    pub expire: Option<u64>,
+    /// Arbitrary metadata, like version, zone or weight.
+    #[serde(default)]
+    pub meta: BTreeMap<String, String>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+}
+
+/// Seconds since the unix epoch.
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Seconds a registration stays valid without a renewal, from `root->lease`.
+pub async fn get_lease(global: &mut MemDataManager) -> u64 {
+    global
+        .get(&Path::from_str("root->lease"))
+        .await
+        .ok()
+        .and_then(|lease_v| lease_v.first().and_then(|s| s.parse().ok()))
+        .unwrap_or(DEFAULT_LEASE)
+}
+
+/// Script to add the web server, or update it if one with the same name, ip and port exists.
+pub fn register_script(web_server: &WebServer) -> Vec<String> {
+    let WebServer { name, ip, port, .. } = web_server;
+    let mut script = vec![
+        format!("$->$:server_exists inner root->web_server {name}<-name"),
+        format!("$->$:server_exists inner $->$:server_exists {ip}<-ip"),
+        format!("$->$:server_exists inner $->$:server_exists {port}<-port"),
+        format!("$->$:web_server if $->$:server_exists ?"),
+        format!("$->$:web_server->name = {name} _"),
+        format!("$->$:web_server->scheme = {} _", web_server.scheme),
+        format!("$->$:web_server->ip = {ip} _"),
+        format!("$->$:web_server->port = {port} _"),
+        format!("$->$:web_server->path = {} _", or_empty(&web_server.path)),
+        format!(
+            "$->$:web_server->sni = {} _",
+            web_server.sni.as_deref().unwrap_or("_")
+        ),
+        format!(
+            "$->$:web_server->expire = {} _",
+            web_server
+                .expire
+                .map(|expire| expire.to_string())
+                .unwrap_or("_".to_string())
+        ),
+    ];
+    script.push(format!("$->$:web_server->meta = _ _"));
+    for (key, value) in &web_server.meta {
+        script.push(format!(
+            "$->$:web_server->meta append $->$:web_server->meta {key}={value}"
+        ));
+    }
+    script.push(format!("$->$:web_server->tag = _ _"));
+    for tag in &web_server.tags {
+        script.push(format!(
+            "$->$:web_server->tag append $->$:web_server->tag {tag}"
+        ));
+    }
+    script.extend([
+        format!("$->$:web_server left $->$:web_server $->$:server_exists"),
+        format!("root->web_server append root->web_server $->$:web_server"),
+    ]);
+    script
+}
+
+/// Script to remove the web server `name` at `ip` and `port`.
+pub fn remove_script(name: &str, ip: &str, port: &str) -> Vec<String> {
+    vec![
+        format!("$->$:web_server inner root->web_server {name}<-name"),
+        format!("$->$:web_server inner $->$:web_server {ip}<-ip"),
+        format!("$->$:web_server inner $->$:web_server {port}<-port"),
+        format!("root->web_server left root->web_server $->$:web_server"),
+    ]
+}
+
+/// Script to dump the web servers named `name`, or all of them.
+pub fn dump_script(name: Option<&str>) -> Vec<String> {
+    let mut script = match name {
+        Some(name) => vec![format!(
+            "$->$:web_server inner root->web_server {name}<-name"
+        )],
+        None => vec![format!("$->$:web_server = root->web_server _")],
+    };
+    for code in [
+        "name", "scheme", "ip", "port", "path", "sni", "expire", "meta", "tag",
+    ] {
+        script.push(format!(
+            "$->$:web_server->$:{code} = $->$:web_server->{code} _"
+        ));
+    }
+    script.push(format!("$->$:output dump $->$:web_server $"));
+    script
+}
+
+/// Parse the output of [`dump_script`].
+pub fn parse_web_servers(web_server_v: &json::JsonValue) -> Vec<WebServer> {
+    web_server_v
+        .members()
+        .filter_map(|web_server| {
+            let value = |code: &str| {
+                web_server[format!("$:{code}").as_str()][0]
+                    .as_str()
+                    .map(|s| s.to_string())
+            };
+            Some(WebServer {
+                name: value("name").unwrap_or_default(),
+                // Servers registered before the scheme was recorded speak http.
+                scheme: value("scheme").unwrap_or_else(default_scheme),
+                ip: value("ip")?,
+                port: value("port")?,
+                path: value("path").unwrap_or_default(),
+                sni: value("sni"),
+                expire: value("expire").and_then(|s| s.parse().ok()),
+                meta: web_server["$:meta"]
+                    .members()
+                    .filter_map(|meta| meta.as_str()?.split_once('='))
+                    .map(|(key, value)| (key.to_string(), value.to_string()))
+                    .collect(),
+                tags: web_server["$:tag"]
+                    .members()
+                    .filter_map(|tag| Some(tag.as_str()?.to_string()))
+                    .collect(),
+            })
+        })
+        .collect()
+}
+
+pub fn check_web_server(web_server: &WebServer) -> err::Result<()> {
+    for (code, value) in [
+        ("name", &web_server.name),
+        ("scheme", &web_server.scheme),
+        ("ip", &web_server.ip),
+        ("port", &web_server.port),
+    ] {
+        check_value(code, value)?;
+    }
+    if !web_server.path.is_empty() {
+        check_value("path", &web_server.path)?;
+    }
+    if let Some(sni) = &web_server.sni {
+        check_value("sni", sni)?;
+    }
+    for (key, value) in &web_server.meta {
+        if key.contains('=') {
+            return Err(err::Error::Other(format!("invalid meta: {key:?}")));
+        }
+        check_value("meta", &format!("{key}={value}"))?;
+    }
+    for tag in &web_server.tags {
+        check_value("tag", tag)?;
+    }
+    Ok(())
+}
+
+/// Register the web server with a lease of `lease` seconds.
+pub async fn register(
+    global: &mut MemDataManager,
+    mut web_server: WebServer,
+    lease: u64,
+) -> err::Result<WebServer> {
+    check_web_server(&web_server)?;
+    web_server.expire = Some(now().saturating_add(lease));
+
+    transaction::execute(global, &register_script(&web_server)).await?;
+    Ok(web_server)
+}
+
+/// Renew the lease of a web server, `None` if it is not registered.
+pub async fn renew(
+    global: &mut MemDataManager,
+    name: &str,
+    ip: &str,
+    port: &str,
+    lease: u64,
+) -> err::Result<Option<u64>> {
+    for (code, value) in [("name", name), ("ip", ip), ("port", port)] {
+        check_value(code, value)?;
+    }
+    let exists = lookup(global, Some(name))
+        .await?
+        .iter()
+        .any(|web_server| web_server.ip == ip && web_server.port == port);
+    if !exists {
+        return Ok(None);
+    }
+    let expire = now().saturating_add(lease);
+    transaction::execute(
+        global,
+        &[
+            format!("$->$:web_server inner root->web_server {name}<-name"),
+            format!("$->$:web_server inner $->$:web_server {ip}<-ip"),
+            format!("$->$:web_server inner $->$:web_server {port}<-port"),
+            format!("$->$:web_server->expire = {expire} _"),
+        ],
+    )
+    .await?;
+    Ok(Some(expire))
+}
+
+pub async fn deregister(
+    global: &mut MemDataManager,
+    name: &str,
+    ip: &str,
+    port: &str,
+) -> err::Result<()> {
+    for (code, value) in [("name", name), ("ip", ip), ("port", port)] {
+        check_value(code, value)?;
+    }
+    transaction::execute(global, &remove_script(name, ip, port)).await?;
+    Ok(())
+}
+
+/// Get the live web servers named `name`, or all of them.
+pub async fn lookup(
+    global: &mut MemDataManager,
+    name: Option<&str>,
+) -> err::Result<Vec<WebServer>> {
+    if let Some(name) = name {
+        check_value("name", name)?;
+    }
+    let rs = transaction::execute(global, &dump_script(name)).await?;
+    let web_server_v =
+        json::parse(&rs_2_str(&rs)).map_err(|e| err::Error::Other(format!("{e}\nwhen lookup")))?;
+    let now = now();
+    Ok(parse_web_servers(&web_server_v)
+        .into_iter()
+        .filter(|web_server| {
+            web_server
+                .expire
+                .map(|expire| expire >= now)
+                .unwrap_or(true)
+        })
+        .collect())
+}
+
+/// Check that a script only writes the web servers named `name`, so that a caller signed as
+/// `name` can't hijack other services, and reads nothing but `root->web_server`, so that it
+/// can't read keys or other settings of the graph.
+///
+/// A variable is scoped if it is selected by `{name}<-name` out of `root->web_server`, or is a
+/// new node, or is derived from scoped variables. Only attributes of scoped variables may be
+/// written, and only scoped variables may be added to or removed from `root->web_server`.
+/// Reverse lookups may reach any node, `root` too, so they are only allowed to narrow web
+/// servers: `{name}<-name` out of `root->web_server` or a scoped variable, `{ip}<-ip` and
+/// `{port}<-port` out of a scoped variable, as the second operand of `inner`.
+pub fn check_script(name: &str, script: &[String]) -> err::Result<()> {
+    let deny = |i: usize, line: &str| {
+        Err(err::Error::Line(
+            i,
+            format!("{name} may not execute: {line}"),
+        ))
+    };
+    let mut scoped_set = std::collections::HashSet::new();
+    for (i, line) in script.iter().enumerate() {
+        let token_v = line.split_whitespace().collect::<Vec<&str>>();
+        if token_v.len() != 4 {
+            return deny(i, line);
+        }
+        let (target, op, first, second) = (token_v[0], token_v[1], token_v[2], token_v[3]);
+        let is_scoped = |var: &str| scoped_set.contains(var);
+        let readable = |token: &str| match token.split_once("<-") {
+            Some(_) => false,
+            None if token.starts_with("root") => {
+                token == "root->web_server" || token.starts_with("root->web_server->")
+            }
+            None => true,
+        };
+        let narrowing = op == "inner"
+            && match second.split_once("<-") {
+                Some((_, "name")) => {
+                    second == format!("{name}<-name")
+                        && (first == "root->web_server" || is_scoped(first))
+                }
+                Some((_, "ip")) | Some((_, "port")) => is_scoped(first),
+                _ => false,
+            };
+        if !readable(first) || !(readable(second) || narrowing) {
+            return deny(i, line);
+        }
+
+        if target.starts_with("root->") {
+            if target == "root->web_server"
+                && (op == "append" || op == "left")
+                && first == "root->web_server"
+                && is_scoped(second)
+            {
+                continue;
+            }
+            return deny(i, line);
+        }
+        let segment_v = target.split("->").collect::<Vec<&str>>();
+        if segment_v.len() < 2 || segment_v[0] != "$" || !segment_v[1].starts_with("$:") {
+            return deny(i, line);
+        }
+        let var = format!("$->{}", segment_v[1]);
+        match segment_v.len() {
+            2 => {
+                let scoped = match op {
+                    "inner" => {
+                        (first == "root->web_server" && second == format!("{name}<-name"))
+                            || is_scoped(first)
+                    }
+                    "if" => is_scoped(first) && second == "?",
+                    "=" => first == "?",
+                    "left" => is_scoped(first),
+                    _ => false,
+                };
+                if scoped {
+                    scoped_set.insert(var);
+                } else {
+                    scoped_set.remove(&var);
+                }
+            }
+            // Temporary attributes, like those for dumping.
+            3 if segment_v[2].starts_with("$:") => (),
+            3 if is_scoped(&var) => {
+                if segment_v[2] == "name" && (op != "=" || first != name) {
+                    return deny(i, line);
+                }
+            }
+            _ => return deny(i, line),
+        }
+    }
+    Ok(())
+}
+
+/// Version of the web servers, it changes only if a web server is added, removed or changed,
+/// not when a lease is renewed.
+pub fn version(web_server_v: &[WebServer]) -> String {
+    let mut web_server_v = web_server_v
+        .iter()
+        .map(|web_server| WebServer {
+            expire: None,
+            ..web_server.clone()
+        })
+        .collect::<Vec<WebServer>>();
+    web_server_v.sort_by(|a, b| (&a.name, &a.ip, &a.port).cmp(&(&b.name, &b.ip, &b.port)));
+    let mut hasher = DefaultHasher::new();
+    for web_server in &web_server_v {
+        serde_json::to_string(web_server)
+            .unwrap_or_default()
+            .hash(&mut hasher);
+    }
+    format!("{:016x}", hasher.finish())
+}
+
+/// A snapshot of the registry, sent to watchers when it changes.
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+pub struct Snapshot {
+    pub version: String,
+    pub web_servers: Vec<WebServer>,
+}
+
+/// Wait until the version of the registry differs from `version` or `timeout` passed.
+pub async fn watch(
+    global: &Mutex<MemDataManager>,
+    version: &str,
+    timeout: Duration,
+) -> err::Result<Option<Snapshot>> {
+    let deadline = time::Instant::now() + timeout;
+    // Subscribed before the registry is read, so that no change is missed in between.
+    let mut receiver = subscription::changes();
+    loop {
+        let web_server_v = lookup(&mut *global.lock().await, None).await?;
+        let current = self::version(&web_server_v);
+        if current != version {
+            return Ok(Some(Snapshot {
+                version: current,
+                web_servers: web_server_v,
+            }));
+        }
+        // Read again once a change writes the registry.
+        loop {
+            match time::timeout_at(deadline, receiver.recv()).await {
+                Err(_) => return Ok(None),
+                Ok(Ok(code_set)) if subscription::touches(&code_set, "web_server") => break,
+                Ok(Ok(_)) => (),
+                Ok(Err(broadcast::error::RecvError::Lagged(_))) => break,
+                Ok(Err(broadcast::error::RecvError::Closed)) => {
+                    return Err(err::Error::Other(format!("closed\nwhen watch")))
+                }
+            }
+        }
+    }
+}
+
+/// Merge the snapshot into `root->web_server`. Web servers of `previous`, the snapshot merged
+/// before, that left the registry are removed, and those of `snapshot` are added or updated with
+/// their leases. Others, like local registrations and discovery caches, are kept.
+pub async fn apply(
+    global: &mut MemDataManager,
+    previous: &[WebServer],
+    snapshot: &Snapshot,
+) -> err::Result<()> {
+    let key = |web_server: &WebServer| {
+        (
+            web_server.name.clone(),
+            web_server.ip.clone(),
+            web_server.port.clone(),
+        )
+    };
+    let key_set = snapshot
+        .web_servers
+        .iter()
+        .map(key)
+        .collect::<std::collections::HashSet<_>>();
+    let mut script = Vec::new();
+    for web_server in previous {
+        if !key_set.contains(&key(web_server)) {
+            script.extend(remove_script(
+                &web_server.name,
+                &web_server.ip,
+                &web_server.port,
+            ));
+        }
+    }
+    for web_server in &snapshot.web_servers {
+        check_web_server(web_server)?;
+        script.extend(register_script(web_server));
+    }
+    if !script.is_empty() {
+        transaction::execute(global, &script).await?;
+    }
+    Ok(())
+}
+
+/// Evicts web servers whose lease expired, so dead instances leave routing on their own.
+pub struct Evictor {
+    global: Arc<Mutex<MemDataManager>>,
+}
+
+impl Evictor {
+    pub fn new(global: Arc<Mutex<MemDataManager>>) -> Self {
+        Self { global }
+    }
+
+    pub async fn run(self) -> io::Result<()> {
+        loop {
+            if let Err(e) = self.execute().await {
+                log::warn!("{e}\nwhen run");
+            }
+
+            time::sleep(SLEEP_TIME).await;
+        }
+    }
+
+    async fn execute(&self) -> io::Result<()> {
+        let mut global = self.global.lock().await;
+        let rs = transaction::execute(&mut *global, &dump_script(None))
+            .await
+            .map_err(|e| io::Error::other(format!("{e}\nwhen execute")))?;
+        let web_server_v = json::parse(&rs_2_str(&rs))
+            .map_err(|e| io::Error::other(format!("{e}\nwhen execute")))?;
+
+        let now = now();
+        let mut script = Vec::new();
+        for web_server in parse_web_servers(&web_server_v) {
+            // Servers without a lease, like static caches, never expire.
+            match web_server.expire {
+                Some(expire) if expire < now => (),
+                _ => continue,
+            }
+            let WebServer { name, ip, port, .. } = &web_server;
+            log::info!("lease of {name} at {ip}:{port} expired");
+            script.extend(remove_script(name, ip, port));
+        }
+        if !script.is_empty() {
+            transaction::execute(&mut *global, &script)
+                .await
+                .map_err(|e| io::Error::other(format!("{e}\nwhen execute")))?;
+        }
+        Ok(())
+    }
+}
+
+/// Values are written into edge scripts, so they must be single tokens meaning nothing but
+/// themselves to the engine. Each code allows only the characters it needs, and none allows
+/// whitespace, `$`, `?`, `<`, `>` or the empty value `_`.
+pub fn check_value(code: &str, value: &str) -> err::Result<()> {
+    let valid = match code {
+        "name" | "code" | "tag" => is_token(value, "-._"),
+        "scheme" => ["http", "https", "unix"].contains(&value),
+        "port" => value.parse::<u16>().is_ok(),
+        // Hosts, IPv6 addresses with zones and socket paths.
+        "ip" | "sni" => is_token(value, "-._:/%[]"),
+        _ => is_token(value, "-._~:/%=,+@!*&'()[]#;"),
+    };
+    if !valid || value == "_" {
+        return Err(err::Error::Other(format!("invalid {code}: {value:?}")));
+    }
+    Ok(())
+}
+
+// Private
+fn is_token(value: &str, extra: &str) -> bool {
+    !value.is_empty()
+        && value
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || extra.contains(c))
+}
+
+fn default_scheme() -> String {
+    "http".to_string()
+}
+
+fn or_empty(value: &str) -> &str {
+    if value.is_empty() {
+        "_"
+    } else {
+        value
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use edge_lib::util::{data::MemDataManager, rs_2_str};
+    use tokio::sync::Mutex;
+
+    use crate::util::transaction;
+
+    /// Every web server in the graph, expired or not.
+    async fn dump(global: &mut MemDataManager) -> Vec<super::WebServer> {
+        let rs = transaction::execute(global, &super::dump_script(None))
+            .await
+            .unwrap();
+        super::parse_web_servers(&json::parse(&rs_2_str(&rs)).unwrap())
+    }
+
+    #[test]
+    fn test_evictor() {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+            .block_on(async {
+                let global = Arc::new(Mutex::new(MemDataManager::new(None)));
+                let web_server = super::WebServer {
+                    name: "api".to_string(),
+                    ip: "::1".to_string(),
+                    port: "80".to_string(),
+                    ..Default::default()
+                };
+                let registered = super::register(&mut *global.lock().await, web_server.clone(), 30)
+                    .await
+                    .unwrap();
+                let expire = registered.expire.unwrap();
+                assert!(expire >= super::now() + 29 && expire <= super::now() + 30);
+                for (ip, expire) in [("::2", Some(super::now() - 1)), ("::3", None)] {
+                    let web_server = super::WebServer {
+                        ip: ip.to_string(),
+                        expire,
+                        ..web_server.clone()
+                    };
+                    transaction::execute(
+                        &mut *global.lock().await,
+                        &super::register_script(&web_server),
+                    )
+                    .await
+                    .unwrap();
+                }
+                assert_eq!(dump(&mut *global.lock().await).await.len(), 3);
+
+                super::Evictor::new(global.clone()).execute().await.unwrap();
+                // The expired one is gone, the leased one and the one without a lease stay.
+                let ip_v = dump(&mut *global.lock().await)
+                    .await
+                    .into_iter()
+                    .map(|web_server| web_server.ip)
+                    .collect::<Vec<String>>();
+                assert_eq!(ip_v.len(), 2);
+                assert!(ip_v.contains(&"::1".to_string()) && ip_v.contains(&"::3".to_string()));
+            })
+    }
+
+    #[test]
+    fn test_apply() {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+            .block_on(async {
+                let mut global = MemDataManager::new(None);
+                let web_server = |name: &str, ip: &str| super::WebServer {
+                    name: name.to_string(),
+                    ip: ip.to_string(),
+                    port: "80".to_string(),
+                    expire: Some(super::now() + 30),
+                    ..Default::default()
+                };
+                // Registered here, not by the watched registry.
+                transaction::execute(
+                    &mut global,
+                    &super::register_script(&web_server("web", "::9")),
+                )
+                .await
+                .unwrap();
+                let first = super::Snapshot {
+                    version: "1".to_string(),
+                    web_servers: vec![web_server("api", "::1"), web_server("api", "::2")],
+                };
+                super::apply(&mut global, &[], &first).await.unwrap();
+                let second = super::Snapshot {
+                    version: "2".to_string(),
+                    web_servers: vec![web_server("api", "::2")],
+                };
+                super::apply(&mut global, &first.web_servers, &second)
+                    .await
+                    .unwrap();
+
+                let web_server_v = dump(&mut global).await;
+                let ip_v = web_server_v
+                    .iter()
+                    .map(|web_server| web_server.ip.as_str())
+                    .collect::<Vec<&str>>();
+                assert_eq!(ip_v.len(), 2);
+                assert!(ip_v.contains(&"::2") && ip_v.contains(&"::9"));
+                assert!(web_server_v
+                    .iter()
+                    .all(|web_server| web_server.expire.is_some()));
+            })
+    }
+
+    #[test]
+    fn test_parse_web_servers() {
+        let web_server_v = json::parse(
+            r#"[
+                {"$:name": ["api"], "$:ip": ["::1"], "$:port": ["80"], "$:expire": ["10"],
+                 "$:meta": ["zone=east"], "$:tag": ["v2"]},
+                {"$:name": ["api"], "$:scheme": ["https"], "$:ip": ["::2"], "$:port": ["443"]},
+                {"$:name": ["api"]}
+            ]"#,
+        )
+        .unwrap();
+        let web_server_v = super::parse_web_servers(&web_server_v);
+        assert_eq!(web_server_v.len(), 2);
+        assert_eq!(web_server_v[0].scheme, "http");
+        assert_eq!(web_server_v[0].expire, Some(10));
+        assert_eq!(web_server_v[0].meta["zone"], "east");
+        assert_eq!(web_server_v[0].tags, vec!["v2".to_string()]);
+        assert_eq!(web_server_v[1].scheme, "https");
+        assert_eq!(web_server_v[1].expire, None);
+    }
+
+    #[test]
+    fn test_check_script() {
+        let web_server = super::WebServer {
+            name: "api".to_string(),
+            ip: "::1".to_string(),
+            port: "80".to_string(),
+            meta: [("zone".to_string(), "west".to_string())].into(),
+            tags: vec!["v2".to_string()],
+            ..Default::default()
+        };
+        assert!(super::check_script("api", &super::register_script(&web_server)).is_ok());
+        assert!(super::check_script("api", &super::remove_script("api", "::1", "80")).is_ok());
+        assert!(super::check_script("api", &super::dump_script(Some("api"))).is_ok());
+        assert!(super::check_script("api", &super::dump_script(None)).is_ok());
+
+        assert!(super::check_script("web", &super::register_script(&web_server)).is_err());
+        assert!(super::check_script("web", &super::remove_script("api", "::1", "80")).is_err());
+        assert!(super::check_script(
+            "web",
+            &[
+                "$->$:web_server = root->web_server _".to_string(),
+                "$->$:web_server->ip = evil _".to_string(),
+            ]
+        )
+        .is_err());
+        assert!(super::check_script("web", &["root->web_server = _ _".to_string()]).is_err());
+        assert!(super::check_script("web", &["root->proxy = _ _".to_string()]).is_err());
+
+        // Selecting by name out of anything but root->web_server scopes nothing.
+        assert!(super::check_script(
+            "web",
+            &[
+                "$->$:node = ? _".to_string(),
+                "$->$:node->path = /api _".to_string(),
+                "$->$:proxy inner $->$:node->path web<-name".to_string(),
+                "$->$:proxy->upstream = http://evil _".to_string(),
+            ]
+        )
+        .is_err());
+        // Nothing but the web servers can be read.
+        assert!(
+            super::check_script("web", &["$->$:output = root->admin_key _".to_string()]).is_err()
+        );
+        assert!(super::check_script(
+            "web",
+            &["$->$:output inner root secret<-admin_key".to_string()]
+        )
+        .is_err());
+        // Nor nodes found by ip or port, like root, but out of its own web servers.
+        assert!(super::check_script(
+            "web",
+            &[
+                "$->$:root inner 0.0.0.0<-ip 80<-port".to_string(),
+                "$->$:output = $->$:root->admin_key _".to_string(),
+            ]
+        )
+        .is_err());
+        assert!(super::check_script(
+            "web",
+            &["$->$:output inner root->web_server 10.0.0.5<-ip".to_string()]
+        )
+        .is_err());
+        assert!(super::check_script("web", &["$->$:output = web<-name _".to_string()]).is_err());
+    }
+
+    #[test]
+    fn test_check_value() {
+        assert!(super::check_value("name", "api").is_ok());
+        assert!(super::check_value("name", "").is_err());
+        assert!(super::check_value("name", "a b").is_err());
+        assert!(super::check_value("name", "a->b").is_err());
+        assert!(super::check_value("name", "_").is_err());
+        assert!(super::check_value("name", "?").is_err());
+        assert!(super::check_value("name", "$->$:x").is_err());
+        assert!(super::check_value("name", "api<-name").is_err());
+        assert!(super::check_value("code", "web_server").is_ok());
+        assert!(super::check_value("ip", "fe80::1%eth0").is_ok());
+        assert!(super::check_value("ip", "/run/api.sock").is_ok());
+        assert!(super::check_value("ip", "1.2.3.4<-ip").is_err());
+        assert!(super::check_value("port", "8080").is_ok());
+        assert!(super::check_value("port", "80a").is_err());
+        assert!(super::check_value("scheme", "ftp").is_err());
+        assert!(super::check_value("meta", "version=2.1.0").is_ok());
+        assert!(super::check_value("upstream", "http://[::1]:8080/api").is_ok());
+        assert!(super::check_value("path", "/api?x").is_err());
+    }
+}
@@ -0,0 +1,461 @@
+//! Proxy routes in `root->proxy`, mounts in `root->mount` and moon servers in `root->moon_server`.
+//!
+//! The middleware reads them from the graph on each request, so a change takes effect on the
+//! next request.
+use std::path::PathBuf;
+
+use edge_lib::util::{
+    data::{AsDataManager, MemDataManager},
+    rs_2_str, Path,
+};
+
+use crate::{
+    err,
+    util::{registry::check_value, transaction},
+};
+
+// Public
+/// A proxy route, requests under `path` go to the service `name` or to `upstreams`.
+#[derive(Clone, Debug, Default, PartialEq, serde::Deserialize, serde::Serialize)]
+pub struct Route {
+    pub path: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// Like `http://127.0.0.1:8080` or `unix:/run/app.sock`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub upstreams: Vec<String>,
+    /// light, cookie:{name} or header:{name}.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sticky: Option<String>,
+    /// tag:{tag}, {key}={value} or prefer:{key}, separated by `,`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub select: Option<String>,
+}
+
+/// A directory served as static files under `path`.
+#[derive(Clone, Debug, Default, PartialEq, serde::Deserialize, serde::Serialize)]
+pub struct Mount {
+    pub path: String,
+    pub dir: String,
+}
+
+pub fn check_route(route: &Route) -> err::Result<()> {
+    check_path(&route.path)?;
+    match (&route.name, route.upstreams.is_empty()) {
+        (Some(name), true) => check_value("name", name)?,
+        (None, false) => {
+            for upstream in &route.upstreams {
+                check_value("upstream", upstream)?;
+                if !is_upstream(upstream) {
+                    return Err(err::Error::Other(format!("invalid upstream: {upstream:?}")));
+                }
+            }
+        }
+        _ => {
+            return Err(err::Error::Other(format!(
+                "either name or upstreams of {} must be given",
+                route.path
+            )))
+        }
+    }
+    if let Some(sticky) = &route.sticky {
+        check_value("sticky", sticky)?;
+        let valid = match sticky.split_once(':') {
+            None => sticky == "light",
+            Some(("cookie", name)) | Some(("header", name)) => !name.is_empty(),
+            _ => false,
+        };
+        if !valid {
+            return Err(err::Error::Other(format!("invalid sticky: {sticky:?}")));
+        }
+    }
+    if let Some(select) = &route.select {
+        check_value("select", select)?;
+        for rule in select.split(',') {
+            let valid = match rule.split_once(':') {
+                Some(("tag", value)) | Some(("prefer", value)) => !value.is_empty(),
+                _ => rule.split_once('=').map(|(key, _)| !key.is_empty()) == Some(true),
+            };
+            if !valid {
+                return Err(err::Error::Other(format!("invalid select: {rule:?}")));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// `root_v` are the directories mounts may serve from, see [`get_mount_roots`].
+pub fn check_mount(mount: &Mount, root_v: &[String]) -> err::Result<()> {
+    check_path(&mount.path)?;
+    check_value("dir", &mount.dir)?;
+    resolve_dir(&mount.dir, root_v)?;
+    Ok(())
+}
+
+/// Real path of the directory `dir`, with symlinks resolved, if it is in any of `root_v`.
+pub fn resolve_dir(dir: &str, root_v: &[String]) -> err::Result<PathBuf> {
+    let real_dir = std::fs::canonicalize(dir)
+        .map_err(|e| err::Error::Other(format!("{e}\nwhen resolve_dir {dir}")))?;
+    if !real_dir.is_dir() {
+        return Err(err::Error::Other(format!("not a directory: {dir}")));
+    }
+    let allowed = root_v.iter().any(|root| {
+        std::fs::canonicalize(root)
+            .map(|real_root| real_dir.starts_with(real_root))
+            .unwrap_or(false)
+    });
+    if !allowed {
+        return Err(err::Error::Other(format!("{dir} is not in mount_roots")));
+    }
+    Ok(real_dir)
+}
+
+/// Directories mounts may serve from, from `root->mount_root`.
+pub async fn get_mount_roots(global: &mut MemDataManager) -> Vec<String> {
+    global
+        .get(&Path::from_str("root->mount_root"))
+        .await
+        .unwrap_or_default()
+}
+
+pub fn check_moon_server(uri: &str) -> err::Result<()> {
+    check_value("uri", uri)?;
+    match reqwest::Url::parse(uri) {
+        Ok(url) if url.scheme() == "http" || url.scheme() == "https" => Ok(()),
+        _ => Err(err::Error::Other(format!("invalid uri: {uri:?}"))),
+    }
+}
+
+/// Script to add the route, or replace the route at its path in place.
+pub fn route_script(route: &Route) -> Vec<String> {
+    let mut script = vec![
+        format!("$->$:proxy_exists inner root->proxy {}<-path", route.path),
+        format!("$->$:proxy if $->$:proxy_exists ?"),
+        format!("$->$:proxy->path = {} _", route.path),
+        format!(
+            "$->$:proxy->name = {} _",
+            route.name.as_deref().unwrap_or("_")
+        ),
+        format!("$->$:proxy->upstream = _ _"),
+    ];
+    for upstream in &route.upstreams {
+        script.push(format!(
+            "$->$:proxy->upstream append $->$:proxy->upstream {upstream}"
+        ));
+    }
+    script.extend([
+        format!(
+            "$->$:proxy->sticky = {} _",
+            route.sticky.as_deref().unwrap_or("_")
+        ),
+        format!(
+            "$->$:proxy->select = {} _",
+            route.select.as_deref().unwrap_or("_")
+        ),
+        format!("$->$:proxy left $->$:proxy $->$:proxy_exists"),
+        format!("root->proxy append root->proxy $->$:proxy"),
+    ]);
+    script
+}
+
+/// Script to add the mount, or replace the mount at its path in place.
+pub fn mount_script(mount: &Mount) -> Vec<String> {
+    vec![
+        format!("$->$:mount_exists inner root->mount {}<-path", mount.path),
+        format!("$->$:mount if $->$:mount_exists ?"),
+        format!("$->$:mount->path = {} _", mount.path),
+        format!("$->$:mount->dir = {} _", mount.dir),
+        format!("$->$:mount left $->$:mount $->$:mount_exists"),
+        format!("root->mount append root->mount $->$:mount"),
+    ]
+}
+
+pub async fn list_routes(global: &mut MemDataManager) -> err::Result<Vec<Route>> {
+    let route_v = dump(
+        global,
+        "proxy",
+        &["path", "name", "upstream", "sticky", "select"],
+    )
+    .await?;
+    Ok(route_v
+        .members()
+        .filter_map(|route| {
+            let value = |code: &str| {
+                route[format!("$:{code}").as_str()][0]
+                    .as_str()
+                    .map(|s| s.to_string())
+            };
+            Some(Route {
+                path: value("path")?,
+                name: value("name"),
+                upstreams: route["$:upstream"]
+                    .members()
+                    .filter_map(|upstream| Some(upstream.as_str()?.to_string()))
+                    .collect(),
+                sticky: value("sticky"),
+                select: value("select"),
+            })
+        })
+        .collect())
+}
+
+pub async fn list_mounts(global: &mut MemDataManager) -> err::Result<Vec<Mount>> {
+    let mount_v = dump(global, "mount", &["path", "dir"]).await?;
+    Ok(mount_v
+        .members()
+        .filter_map(|mount| {
+            Some(Mount {
+                path: mount["$:path"][0].as_str()?.to_string(),
+                dir: mount["$:dir"][0].as_str()?.to_string(),
+            })
+        })
+        .collect())
+}
+
+/// Add the route, or replace the route at its path.
+pub async fn put_route(global: &mut MemDataManager, route: &Route) -> err::Result<()> {
+    check_route(route)?;
+    transaction::execute(global, &route_script(route)).await?;
+    Ok(())
+}
+
+/// Add the mount, or replace the mount at its path.
+pub async fn put_mount(global: &mut MemDataManager, mount: &Mount) -> err::Result<()> {
+    check_mount(mount, &get_mount_roots(global).await)?;
+    transaction::execute(global, &mount_script(mount)).await?;
+    Ok(())
+}
+
+/// Remove the route at `path`, `false` if there is none.
+pub async fn delete_route(global: &mut MemDataManager, path: &str) -> err::Result<bool> {
+    remove(global, "proxy", path).await
+}
+
+/// Remove the mount at `path`, `false` if there is none.
+pub async fn delete_mount(global: &mut MemDataManager, path: &str) -> err::Result<bool> {
+    remove(global, "mount", path).await
+}
+
+pub async fn list_moon_servers(global: &mut MemDataManager) -> err::Result<Vec<String>> {
+    global
+        .get(&Path::from_str("root->moon_server"))
+        .await
+        .map_err(|e| err::Error::Other(e.message().to_string()))
+}
+
+/// Add a moon server, `false` if it is already there.
+pub async fn add_moon_server(global: &mut MemDataManager, uri: &str) -> err::Result<bool> {
+    check_moon_server(uri)?;
+    if list_moon_servers(global).await?.iter().any(|s| s == uri) {
+        return Ok(false);
+    }
+    transaction::execute(
+        global,
+        &[format!("root->moon_server append root->moon_server {uri}")],
+    )
+    .await?;
+    Ok(true)
+}
+
+/// Remove a moon server, `false` if it is not there.
+pub async fn delete_moon_server(global: &mut MemDataManager, uri: &str) -> err::Result<bool> {
+    check_value("uri", uri)?;
+    if !list_moon_servers(global).await?.iter().any(|s| s == uri) {
+        return Ok(false);
+    }
+    transaction::execute(
+        global,
+        &[format!("root->moon_server left root->moon_server {uri}")],
+    )
+    .await?;
+    Ok(true)
+}
+
+// Private
+/// Whether the upstream is `unix:{socket path}` or a http(s) url with a host.
+fn is_upstream(upstream: &str) -> bool {
+    if let Some(socket) = upstream.strip_prefix("unix:") {
+        return !socket.is_empty();
+    }
+    match reqwest::Url::parse(upstream) {
+        Ok(url) => (url.scheme() == "http" || url.scheme() == "https") && url.host().is_some(),
+        Err(_) => false,
+    }
+}
+
+/// Routes and mounts are matched as prefixes of request paths.
+fn check_path(path: &str) -> err::Result<()> {
+    check_value("path", path)?;
+    if !path.starts_with('/') {
+        return Err(err::Error::Other(format!(
+            "path must start with /: {path:?}"
+        )));
+    }
+    Ok(())
+}
+
+/// Dump the nodes in `root->{code}` with their attributes `code_v`.
+async fn dump(
+    global: &mut MemDataManager,
+    code: &str,
+    code_v: &[&str],
+) -> err::Result<json::JsonValue> {
+    let mut script = vec![format!("$->$:node = root->{code} _")];
+    for code in code_v {
+        script.push(format!("$->$:node->$:{code} = $->$:node->{code} _"));
+    }
+    script.push(format!("$->$:output dump $->$:node $"));
+    let rs = transaction::execute(global, &script).await?;
+    json::parse(&rs_2_str(&rs)).map_err(|e| err::Error::Other(format!("{e}\nwhen dump")))
+}
+
+/// Remove the node at `path` from `root->{code}`, `false` if there is none.
+async fn remove(global: &mut MemDataManager, code: &str, path: &str) -> err::Result<bool> {
+    check_value("path", path)?;
+    let node_v = global
+        .get(&Path::from_str(&format!("root->{code}")))
+        .await
+        .map_err(|e| err::Error::Other(e.message().to_string()))?;
+    let mut exists = false;
+    for node in &node_v {
+        let path_v = global
+            .get(&Path::from_str(&format!("{node}->path")))
+            .await
+            .map_err(|e| err::Error::Other(e.message().to_string()))?;
+        exists |= path_v.first().map(|s| s.as_str()) == Some(path);
+    }
+    if !exists {
+        return Ok(false);
+    }
+    transaction::execute(
+        global,
+        &[
+            format!("$->$:node inner root->{code} {path}<-path"),
+            format!("root->{code} left root->{code} $->$:node"),
+        ],
+    )
+    .await?;
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use edge_lib::util::{
+        data::{AsDataManager, MemDataManager},
+        Path,
+    };
+
+    use super::{check_mount, check_route, Mount, Route};
+
+    #[test]
+    fn test_check_route() {
+        let route = Route {
+            path: "/api".to_string(),
+            name: Some("api".to_string()),
+            sticky: Some("cookie:session".to_string()),
+            select: Some("tag:v2,zone=east,prefer:zone".to_string()),
+            ..Default::default()
+        };
+        assert!(check_route(&route).is_ok());
+        assert!(check_route(&Route {
+            upstreams: vec!["http://127.0.0.1:8080".to_string()],
+            ..route.clone()
+        })
+        .is_err());
+        assert!(check_route(&Route {
+            path: "api".to_string(),
+            ..route.clone()
+        })
+        .is_err());
+        assert!(check_route(&Route {
+            sticky: Some("cookie:".to_string()),
+            ..route.clone()
+        })
+        .is_err());
+        assert!(check_route(&Route {
+            name: None,
+            upstreams: vec!["127.0.0.1:8080".to_string()],
+            ..route.clone()
+        })
+        .is_err());
+        assert!(check_route(&Route {
+            name: None,
+            upstreams: vec!["http://".to_string()],
+            ..route.clone()
+        })
+        .is_err());
+        assert!(check_route(&Route {
+            name: None,
+            upstreams: vec!["unix:/run/api.sock".to_string()],
+            ..route
+        })
+        .is_ok());
+    }
+
+    #[test]
+    fn test_check_mount() {
+        let root = std::env::temp_dir().join(format!("light-test-mount-{}", std::process::id()));
+        let www = root.join("www");
+        std::fs::create_dir_all(&www).unwrap();
+        let root_v = vec![root.to_string_lossy().to_string()];
+        let mount = |dir: &std::path::Path| Mount {
+            path: "/static".to_string(),
+            dir: dir.to_string_lossy().to_string(),
+        };
+        assert!(check_mount(&mount(&www), &root_v).is_ok());
+        assert!(check_mount(&mount(&root), &root_v).is_ok());
+        assert!(check_mount(&mount(std::path::Path::new("/etc")), &root_v).is_err());
+        assert!(check_mount(&mount(&www.join("..").join("..")), &root_v).is_err());
+        assert!(check_mount(&mount(&www), &[]).is_err());
+
+        // Symlinks are followed before they are checked.
+        let link = root.join("etc");
+        let _ = std::fs::remove_file(&link);
+        std::os::unix::fs::symlink("/etc", &link).unwrap();
+        assert!(check_mount(&mount(&link), &root_v).is_err());
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_route_script() {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+            .block_on(async {
+                let mut global = MemDataManager::new(None);
+                let route = Route {
+                    path: "/api".to_string(),
+                    upstreams: vec!["http://127.0.0.1:8080".to_string()],
+                    ..Default::default()
+                };
+                super::put_route(&mut global, &route).await.unwrap();
+                super::put_route(
+                    &mut global,
+                    &Route {
+                        path: "/web".to_string(),
+                        name: Some("web".to_string()),
+                        ..Default::default()
+                    },
+                )
+                .await
+                .unwrap();
+                let proxy_v = global.get(&Path::from_str("root->proxy")).await.unwrap();
+
+                // Replaced in place, keeping its node and its place.
+                let route = Route {
+                    upstreams: vec!["http://127.0.0.1:9090".to_string()],
+                    sticky: Some("light".to_string()),
+                    ..route
+                };
+                super::put_route(&mut global, &route).await.unwrap();
+                assert_eq!(
+                    global.get(&Path::from_str("root->proxy")).await.unwrap(),
+                    proxy_v
+                );
+                let route_v = super::list_routes(&mut global).await.unwrap();
+                assert_eq!(route_v.len(), 2);
+                assert_eq!(route_v[0], route);
+            });
+    }
+}
@@ -1,28 +1,53 @@
 //! Server that provides services.
+pub mod auth;
 mod middle_ware;
 mod service;
 
-use std::{io, sync::Arc};
+use std::{future::Future, io, sync::Arc};
 
 use actix_web::{web, HttpServer};
 use edge_lib::util::{
-    data::MemDataManager,
+    data::{AsDataManager, MemDataManager},
     engine::{AsEdgeEngine, EdgeEngine},
+    Path,
 };
 use tokio::sync::Mutex;
 
+use super::{gossip, native, sign, store};
+
 // Public
 pub struct WebServer {
     global: Arc<Mutex<MemDataManager>>,
+    cluster: Option<Arc<gossip::Cluster>>,
+    store: Option<Arc<store::Store>>,
 }
 
 impl WebServer {
     pub fn new(global: Arc<Mutex<MemDataManager>>) -> Self {
-        Self { global }
+        Self {
+            global,
+            cluster: None,
+            store: None,
+        }
+    }
+
+    /// Serve the gossip of the cluster under `path`.
+    pub fn gossip(mut self, cluster: Arc<gossip::Cluster>) -> Self {
+        self.cluster = Some(cluster);
+        self
+    }
+
+    /// Persist changes made through `path` in the store.
+    pub fn store(mut self, store: Arc<store::Store>) -> Self {
+        self.store = Some(store);
+        self
     }
 
     /// Server run itself. This will block current thread.
-    pub async fn run(self) -> io::Result<()> {
+    ///
+    /// On SIGTERM or SIGINT the server stops accepting connections, awaits `on_stop`, then
+    /// drains the requests in flight and returns.
+    pub async fn run(self, on_stop: impl Future<Output = ()>) -> io::Result<()> {
         let mut global = self.global.lock().await;
 
         let mut edge_engine = EdgeEngine::new(&mut *global);
@@ -34,11 +59,22 @@ impl WebServer {
                 "$->$:output += $->$:output root->port".to_string(),
                 "$->$:output += $->$:output root->path".to_string(),
                 "$->$:output += $->$:output root->src".to_string(),
+                "$->$:output += $->$:output root->scheme".to_string(),
+                "$->$:output += $->$:output root->registry".to_string(),
             ])
             .await
             .unwrap();
 
         drop(edge_engine);
+        let discovery_v = global
+            .get(&Path::from_str("root->discovery"))
+            .await
+            .unwrap();
+        let tls_ca_v = global.get(&Path::from_str("root->tls_ca")).await.unwrap();
+        let tls_insecure_v = global
+            .get(&Path::from_str("root->tls_insecure"))
+            .await
+            .unwrap();
         drop(global);
 
         let name = &rs[0];
@@ -46,15 +82,63 @@ impl WebServer {
         let port = &rs[2];
         let path = rs[3].clone();
         let src = rs[4].clone();
+        let scheme = &rs[5];
+        let registry = rs[6] == "true";
 
         let domain = format!("{ip}:{port}");
-        log::info!("http service {name} uri: http://{domain}{path}");
+        if scheme == "unix" {
+            log::info!("http service {name} uri: unix:{ip}{path}");
+        } else {
+            log::info!("http service {name} uri: http://{domain}{path}");
+        }
+        // Shared by the workers so that a nonce is only accepted once.
+        let verifier = web::Data::new(sign::Verifier::new());
+        // Shared by the workers so that backends and clients load and cache once.
+        let proxy = middle_ware::Proxy::new(
+            &discovery_v,
+            tls_ca_v.first().map(|ca| ca.as_str()),
+            tls_insecure_v.first().map(|s| s.as_str()) == Some("true"),
+        )
+        .map_err(|e| io::Error::other(format!("{e}\nwhen run")))?;
+        let cluster = self.cluster.clone();
+        let store = self.store.clone();
         let server = HttpServer::new(move || {
-            actix_web::App::new()
+            let mut app = actix_web::App::new()
                 .app_data(web::Data::new(self.global.clone()))
-                .wrap(middle_ware::Proxy::new())
-                .service(service::config(&path, &src))
-        });
-        server.bind(&domain)?.run().await
+                .app_data(verifier.clone());
+            if let Some(cluster) = &cluster {
+                app = app.app_data(web::Data::new(cluster.clone()));
+            }
+            if let Some(store) = &store {
+                app = app.app_data(web::Data::new(store.clone()));
+            }
+            app.wrap(proxy.clone()).service(service::config(
+                &path,
+                &src,
+                registry,
+                cluster.is_some(),
+            ))
+        })
+        .disable_signals();
+        let server = if scheme == "unix" {
+            // Serve on the unix socket at `ip`.
+            server.bind_uds(ip)?.run()
+        } else {
+            server.bind(&domain)?.run()
+        };
+
+        let handle = server.handle();
+        let mut server_task = tokio::spawn(server);
+        tokio::select! {
+            rs = &mut server_task => return rs?,
+            rs = native::wait_for_stop_signal() => rs?,
+        }
+
+        log::info!("stopping");
+        let stopped = handle.stop(true);
+        on_stop.await;
+        stopped.await;
+        log::info!("stopped");
+        server_task.await?
     }
 }
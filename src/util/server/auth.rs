@@ -0,0 +1,525 @@
+//! Policies deciding who may use the admin entries of light, and what scripts they may run.
+use std::collections::HashMap;
+
+use actix_web::{http::header::AUTHORIZATION, HttpRequest};
+use edge_lib::util::{
+    data::{AsDataManager, MemDataManager},
+    Path,
+};
+
+use crate::err;
+
+// Public
+/// A policy, parsed from a list separated by `,`. A request is allowed if any policy allows it.
+///
+/// - `open`: anyone.
+/// - `loopback`: clients on the loopback address, or on the unix socket if light serves on one.
+///   Clients without an address are denied otherwise.
+/// - `bearer:{token}`: clients sending `Authorization: Bearer {token}`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Policy {
+    Open,
+    Loopback,
+    Bearer(String),
+}
+
+impl Policy {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s.split_once(':') {
+            None if s == "open" => Some(Self::Open),
+            None if s == "loopback" => Some(Self::Loopback),
+            Some(("bearer", token)) if !token.is_empty() => Some(Self::Bearer(token.to_string())),
+            _ => None,
+        }
+    }
+
+    pub fn parse_list(s: &str) -> Vec<Self> {
+        s.split(',')
+            .map(|s| s.trim())
+            .filter(|s| !s.is_empty())
+            .filter_map(|s| {
+                let policy = Self::parse(s);
+                if policy.is_none() {
+                    // Unknown policies allow nobody.
+                    log::warn!("unknown auth policy: {s}");
+                }
+                policy
+            })
+            .collect()
+    }
+
+    /// `unix` tells if light serves on a unix socket, where clients have no address.
+    pub fn allow(&self, req: &HttpRequest, unix: bool) -> bool {
+        match self {
+            Self::Open => true,
+            Self::Loopback => req
+                .peer_addr()
+                .map(|addr| addr.ip().is_loopback())
+                .unwrap_or(unix),
+            Self::Bearer(token) => req
+                .headers()
+                .get(AUTHORIZATION)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.strip_prefix("Bearer "))
+                .map(|v| eq_const(v.as_bytes(), token.as_bytes()))
+                .unwrap_or(false),
+        }
+    }
+}
+
+pub fn allow(policy_v: &[Policy], req: &HttpRequest, unix: bool) -> bool {
+    policy_v.iter().any(|policy| policy.allow(req, unix))
+}
+
+/// Whether unsigned calls may use the admin entries and gossip, from `root->execute_open`.
+pub async fn is_open(global: &mut MemDataManager) -> bool {
+    let open_v = global
+        .get(&Path::from_str("root->execute_open"))
+        .await
+        .unwrap_or_default();
+    open_v.first().map(|s| s.as_str()) == Some("true")
+}
+
+/// Who made a call to the entries served under `path`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Caller {
+    /// Unsigned.
+    Anonymous,
+    /// Signed by the key of an admin, with the scopes of the call.
+    Admin(String, Vec<Scope>),
+    /// Signed by the key of a service, named as its service.
+    Service(String),
+}
+
+impl Caller {
+    /// Whether the caller may read the audit log, anonymous callers only get here while
+    /// [`is_open`].
+    pub fn reads_audit(&self) -> bool {
+        match self {
+            Self::Anonymous => true,
+            Self::Admin(_, scope_v) => {
+                scope_v.contains(&Scope::All) || scope_v.contains(&Scope::Audit)
+            }
+            Self::Service(_) => false,
+        }
+    }
+
+    /// Name in the audit log.
+    pub fn name(&self) -> String {
+        match self {
+            Self::Anonymous => "anonymous".to_string(),
+            Self::Admin(id, _) => format!("admin:{id}"),
+            Self::Service(name) => format!("service:{name}"),
+        }
+    }
+}
+
+/// A scope of an admin, parsed from a list separated by `,`. A script is allowed if every path it
+/// writes is allowed by any scope. Scripts may read anything.
+///
+/// - `all`: anything.
+/// - `read`: nothing but temporaries of the script.
+/// - `audit`: as `read`, and read the audit log.
+/// - `write:{code}`: paths under `root->{code}`, like `write:proxy` or `write:web_server`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Scope {
+    All,
+    Read,
+    Audit,
+    Write(String),
+}
+
+impl Scope {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s.split_once(':') {
+            None if s == "all" => Some(Self::All),
+            None if s == "read" => Some(Self::Read),
+            None if s == "audit" => Some(Self::Audit),
+            Some(("write", code)) if !code.is_empty() && !code.contains("->") => {
+                Some(Self::Write(code.to_string()))
+            }
+            _ => None,
+        }
+    }
+
+    pub fn parse_list(s: &str) -> Vec<Self> {
+        s.split(',')
+            .map(|s| s.trim())
+            .filter(|s| !s.is_empty())
+            .filter_map(|s| {
+                let scope = Self::parse(s);
+                if scope.is_none() {
+                    // Unknown scopes allow nothing.
+                    log::warn!("unknown scope: {s}");
+                }
+                scope
+            })
+            .collect()
+    }
+
+    /// Whether the path, starting at `root`, may be written.
+    pub fn allow(&self, path: &str) -> bool {
+        match self {
+            Self::All => true,
+            Self::Read | Self::Audit => false,
+            Self::Write(code) => {
+                let prefix = format!("root->{code}");
+                path == prefix || path.starts_with(&format!("{prefix}->"))
+            }
+        }
+    }
+
+    /// Whether this scope grants all that `other` does.
+    fn covers(&self, other: &Scope) -> bool {
+        match (self, other) {
+            (Self::All, _) | (_, Self::Read) => true,
+            (_, Self::All) | (Self::Read, _) => false,
+            (Self::Audit, Self::Audit) => true,
+            (Self::Audit, _) | (_, Self::Audit) => false,
+            (Self::Write(_), Self::Write(code)) => self.allow(&format!("root->{code}")),
+        }
+    }
+}
+
+pub fn allow_write(scope_v: &[Scope], path: &str) -> bool {
+    scope_v.iter().any(|scope| scope.allow(path))
+}
+
+/// Narrow the scopes of a key to those asked by a call, `None` if it asks for more.
+pub fn narrow(key_scope_v: &[Scope], asked_v: &[Scope]) -> Option<Vec<Scope>> {
+    if asked_v
+        .iter()
+        .all(|asked| key_scope_v.iter().any(|scope| scope.covers(asked)))
+    {
+        Some(asked_v.to_vec())
+    } else {
+        None
+    }
+}
+
+/// Check that the script writes only paths allowed by the scopes.
+///
+/// What it writes there must be values, new nodes or nodes traced from under the same
+/// `root->{code}`, so that no node, like `root` itself, can be listed where a scope reaches it
+/// through a path it doesn't belong to. See also [`transaction::execute_scoped`], checking what
+/// the script really wrote.
+///
+/// [`transaction::execute_scoped`]: crate::util::transaction::execute_scoped
+pub fn check_script(scope_v: &[Scope], script: &[String]) -> err::Result<()> {
+    if scope_v.contains(&Scope::All) {
+        return Ok(());
+    }
+    for write in trace(script)? {
+        let line = &script[write.line];
+        if !allow_write(scope_v, &write.path) {
+            return Err(err::Error::Line(
+                write.line,
+                format!("{} is out of scope: {line}", write.path),
+            ));
+        }
+        let owner = write
+            .path
+            .split("->")
+            .take(2)
+            .collect::<Vec<&str>>()
+            .join("->");
+        for (operand, origin) in write.value_v {
+            let foreign = match origin {
+                Some(origin) => origin
+                    .path_v
+                    .iter()
+                    .find(|path| **path != owner && !path.starts_with(&format!("{owner}->")))
+                    .cloned(),
+                None if is_value(&operand) => None,
+                None => Some(operand),
+            };
+            if let Some(foreign) = foreign {
+                return Err(err::Error::Line(
+                    write.line,
+                    format!("{foreign} may not be written under {owner}: {line}"),
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Attributes of `root` listing nodes. A node must be reached through the one it is listed in
+/// only, see [`transaction::execute_scoped`].
+///
+/// [`transaction::execute_scoped`]: crate::util::transaction::execute_scoped
+pub const NODE_CODE_V: [&str; 3] = ["proxy", "mount", "web_server"];
+
+/// Paths starting at `root` the script may write, with the index of the line writing each.
+///
+/// Variables of the script are traced back to the paths they were read from, so that a node
+/// reached through `$->$:v = root->web_server _` is as guarded as `root->web_server` itself.
+/// Attributes of new nodes and temporaries are dropped with the script, so they are left out.
+/// Nodes that can't be traced, like those of `{value}<-{code}`, of a value naming a node or of
+/// attributes of new nodes, may be any node, so writing them fails.
+pub fn written_paths(script: &[String]) -> err::Result<Vec<(usize, String)>> {
+    Ok(trace(script)?
+        .into_iter()
+        .map(|write| (write.line, write.path))
+        .collect())
+}
+
+// Private
+/// A path a line of a script may write.
+struct Write {
+    line: usize,
+    path: String,
+    /// Operands whose values the line writes there, with where their nodes may come from.
+    value_v: Vec<(String, Option<Origin>)>,
+}
+
+/// Paths starting at `root` the script may write, see [`written_paths`].
+fn trace(script: &[String]) -> err::Result<Vec<Write>> {
+    // Origins of the nodes each variable may hold, `None` if untraced.
+    let mut origin_mp: HashMap<String, Option<Origin>> = HashMap::new();
+    let mut write_v = Vec::new();
+    for (i, line) in script.iter().enumerate() {
+        let token_v = line.split_whitespace().collect::<Vec<&str>>();
+        if token_v.len() != 4 {
+            return Err(err::Error::Line(i, format!("unexpected line: {line}")));
+        }
+        // What `left` and `inner` are given second only filters the first.
+        let value_v = match token_v[1] {
+            "left" | "inner" => &token_v[2..3],
+            _ => &token_v[2..],
+        }
+        .iter()
+        // Values already there are no new reach.
+        .filter(|operand| **operand != token_v[0])
+        .map(|operand| (operand.to_string(), origins(&origin_mp, operand)))
+        .collect::<Vec<(String, Option<Origin>)>>();
+        let segment_v = token_v[0].split("->").collect::<Vec<&str>>();
+        match segment_v[0] {
+            "root" => write_v.push(Write {
+                line: i,
+                path: token_v[0].to_string(),
+                value_v,
+            }),
+            "$" if segment_v.len() == 2 => {
+                let origin = match (
+                    token_v[1],
+                    origins(&origin_mp, token_v[2]),
+                    origins(&origin_mp, token_v[3]),
+                ) {
+                    // Nodes in both, so in either.
+                    ("inner", Some(origin), None) | ("inner", None, Some(origin)) => Some(origin),
+                    (_, Some(origin), Some(more)) => Some(origin.union(more)),
+                    _ => None,
+                };
+                origin_mp.insert(segment_v[1].to_string(), origin);
+            }
+            "$" if segment_v[segment_v.len() - 1].starts_with("$:") => (),
+            "$" => {
+                let (attr, node) = segment_v.split_last().unwrap();
+                let origin = origins(&origin_mp, &node.join("->"))
+                    .ok_or_else(|| err::Error::Line(i, format!("untraced target: {line}")))?;
+                write_v.extend(origin.path_v.into_iter().map(|origin| Write {
+                    line: i,
+                    path: format!("{origin}->{attr}"),
+                    value_v: value_v.clone(),
+                }));
+            }
+            _ => return Err(err::Error::Line(i, format!("unexpected target: {line}"))),
+        }
+    }
+    Ok(write_v)
+}
+
+/// Where the nodes of a variable may come from.
+#[derive(Clone, Default)]
+struct Origin {
+    path_v: Vec<String>,
+    /// May hold new nodes.
+    new: bool,
+}
+
+impl Origin {
+    fn union(mut self, other: Origin) -> Origin {
+        self.path_v.extend(other.path_v);
+        self.new |= other.new;
+        self
+    }
+}
+
+/// Whether an operand is a value rather than a path or a lookup.
+fn is_value(operand: &str) -> bool {
+    !operand.contains("->")
+        && !operand.contains("<-")
+        && !["root", "$", "?", "_"].contains(&operand)
+}
+
+/// Where the nodes of an operand may come from, `None` if they can't be traced.
+fn origins(origin_mp: &HashMap<String, Option<Origin>>, operand: &str) -> Option<Origin> {
+    if operand.contains("<-") {
+        return None;
+    }
+    let segment_v = operand.split("->").collect::<Vec<&str>>();
+    match segment_v[0] {
+        "root" => Some(Origin {
+            path_v: vec![operand.to_string()],
+            new: false,
+        }),
+        "$" if segment_v.len() > 1 => match origin_mp.get(segment_v[1]) {
+            Some(Some(origin)) => {
+                // Attributes of new nodes may hold any node.
+                if origin.new && segment_v.len() > 2 {
+                    return None;
+                }
+                Some(Origin {
+                    path_v: origin
+                        .path_v
+                        .iter()
+                        .map(|origin| {
+                            std::iter::once(origin.as_str())
+                                .chain(segment_v[2..].iter().copied())
+                                .collect::<Vec<&str>>()
+                                .join("->")
+                        })
+                        .collect(),
+                    new: origin.new,
+                })
+            }
+            Some(None) => None,
+            // Unset variables hold nothing.
+            None => Some(Origin::default()),
+        },
+        "?" if segment_v.len() == 1 => Some(Origin {
+            path_v: Vec::new(),
+            new: true,
+        }),
+        "_" if segment_v.len() == 1 => Some(Origin::default()),
+        // Values may name any node.
+        _ => None,
+    }
+}
+
+/// Compare without leaking where the first difference is.
+fn eq_const(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::test::TestRequest;
+
+    use super::{check_script, narrow, Policy, Scope};
+
+    #[test]
+    fn test_allow() {
+        let policy_v = Policy::parse_list("loopback,bearer:secret");
+        let req = TestRequest::default()
+            .peer_addr("127.0.0.1:8080".parse().unwrap())
+            .to_http_request();
+        assert!(super::allow(&policy_v, &req, false));
+        let req = TestRequest::default()
+            .peer_addr("10.0.0.2:8080".parse().unwrap())
+            .to_http_request();
+        assert!(!super::allow(&policy_v, &req, false));
+
+        // Clients without an address are local only on the unix socket.
+        let req = TestRequest::default().to_http_request();
+        assert!(!super::allow(&policy_v, &req, false));
+        assert!(super::allow(&policy_v, &req, true));
+        let req = TestRequest::default()
+            .insert_header(("Authorization", "Bearer secret"))
+            .to_http_request();
+        assert!(super::allow(&policy_v, &req, false));
+    }
+
+    #[test]
+    fn test_check_script() {
+        let proxy_v = Scope::parse_list("write:proxy");
+        let script = [
+            "$->$:proxy = ? _",
+            "$->$:proxy->path = /api _",
+            "root->proxy append root->proxy $->$:proxy",
+        ]
+        .map(|s| s.to_string());
+        assert!(check_script(&proxy_v, &script).is_ok());
+        assert!(check_script(&Scope::parse_list("read"), &script).is_err());
+
+        // Nodes reached through variables keep their paths.
+        let script = [
+            "$->$:web_server = root->web_server _",
+            "$->$:ip = $->$:web_server->ip _",
+            "$->$:web_server->ip = ::1 _",
+        ]
+        .map(|s| s.to_string());
+        assert!(check_script(&proxy_v, &script).is_err());
+        assert!(check_script(&Scope::parse_list("read,write:web_server"), &script).is_ok());
+        assert!(check_script(&Scope::parse_list("read"), &script[..2]).is_ok());
+
+        // Nodes found by value may be any node.
+        let script = [
+            "$->$:proxy = /api<-path _",
+            "$->$:proxy->upstream = http://evil _",
+        ]
+        .map(|s| s.to_string());
+        assert!(check_script(&Scope::parse_list("write:web_server"), &script).is_err());
+        assert!(check_script(&proxy_v, &script).is_err());
+        let script = [
+            "$->$:node = 0123abcd _",
+            "$->$:node->upstream = http://evil _",
+        ]
+        .map(|s| s.to_string());
+        assert!(check_script(&proxy_v, &script).is_err());
+
+        // Unless they are picked out of a traced path.
+        let script = [
+            "$->$:proxy inner root->proxy /api<-path",
+            "$->$:proxy->upstream = http://127.0.0.1:8080 _",
+        ]
+        .map(|s| s.to_string());
+        assert!(check_script(&proxy_v, &script).is_ok());
+        assert!(check_script(&Scope::parse_list("write:web_server"), &script).is_err());
+
+        // Nor taken out of new nodes, which may have been given any node.
+        let script = [
+            "$->$:node = ? _",
+            "$->$:node->child = root->web_server _",
+            "$->$:web_server = $->$:node->child _",
+            "$->$:web_server->ip = ::1 _",
+        ]
+        .map(|s| s.to_string());
+        assert!(check_script(&proxy_v, &script).is_err());
+        assert!(check_script(&proxy_v, &script[..2]).is_ok());
+
+        // No node may be listed under a scope it doesn't come from.
+        for node in ["root", "root->web_server", "$->$:web_server", "0.0.0.0<-ip"] {
+            let script = [
+                "$->$:web_server = root->web_server _".to_string(),
+                format!("root->proxy append root->proxy {node}"),
+            ];
+            assert!(check_script(&proxy_v, &script).is_err());
+        }
+        let script = [
+            "$->$:proxy inner root->proxy /api<-path",
+            "$->$:proxy->upstream append $->$:proxy->upstream http://127.0.0.1:8080",
+            "root->proxy left root->proxy root->web_server",
+        ]
+        .map(|s| s.to_string());
+        assert!(check_script(&proxy_v, &script).is_ok());
+    }
+
+    #[test]
+    fn test_narrow() {
+        let key_scope_v = Scope::parse_list("read,write:proxy");
+        assert_eq!(
+            narrow(&key_scope_v, &Scope::parse_list("read")),
+            Some(vec![Scope::Read])
+        );
+        assert_eq!(
+            narrow(&key_scope_v, &Scope::parse_list("write:web_server")),
+            None
+        );
+        assert_eq!(narrow(&key_scope_v, &Scope::parse_list("all")), None);
+        assert_eq!(narrow(&key_scope_v, &Scope::parse_list("audit")), None);
+        assert!(narrow(&Scope::parse_list("audit"), &Scope::parse_list("read")).is_some());
+        assert!(narrow(&[Scope::All], &Scope::parse_list("write:proxy")).is_some());
+    }
+}
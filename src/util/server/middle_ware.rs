@@ -1,8 +1,9 @@
+use actix_files::NamedFile;
 use actix_http::body::BoxBody;
 use actix_web::{
     dev::{forward_ready, Service, Transform},
     dev::{ServiceRequest, ServiceResponse},
-    web, Error,
+    web, Error, HttpResponse,
 };
 use edge_lib::util::{
     data::{AsDataManager, MemDataManager},
@@ -15,11 +16,18 @@ use std::{
 };
 use tokio::sync::Mutex;
 
+use crate::{
+    err,
+    util::{audit, route},
+};
+
 mod proxy;
 
 // Public
 pub struct ProxyMiddleware<S> {
     service: Arc<S>,
+    chain: Arc<proxy::discovery::Chain>,
+    clients: Arc<proxy::client::Clients>,
 }
 
 impl<S> Service<ServiceRequest> for ProxyMiddleware<S>
@@ -35,7 +43,10 @@ where
 
     fn call(&self, req: ServiceRequest) -> Self::Future {
         let service = self.service.clone();
-        Box::pin(async move {
+        let chain = self.chain.clone();
+        let clients = self.clients.clone();
+        let actor = audit::Actor::request(req.peer_addr().map(|addr| addr.ip().to_string()));
+        Box::pin(audit::scope(actor, async move {
             {
                 let path = req.path().to_string();
                 log::info!("request: {path}");
@@ -47,8 +58,22 @@ where
 
                 let mut global = global_mutex.lock().await;
 
-                if path.starts_with(proxy::MOON_SERVICE_PATH) {
-                    return Ok(proxy::respone_moon(&path, &mut *global, req).await);
+                let moon_path_v = global
+                    .get(&Path::from_str("root->moon_path"))
+                    .await
+                    .unwrap();
+                if let Some(moon_path) = moon_path_v.first() {
+                    if path.starts_with(moon_path) {
+                        drop(global);
+                        return Ok(proxy::respone_moon(
+                            &path,
+                            moon_path,
+                            &global_mutex,
+                            req,
+                            &clients,
+                        )
+                        .await);
+                    }
                 }
 
                 let proxy_v = global.get(&Path::from_str("root->proxy")).await.unwrap();
@@ -60,20 +85,51 @@ where
                         .unwrap();
 
                     if path.starts_with(&fake_path_v[0]) {
+                        drop(global);
                         return Ok(proxy::respone(
                             &path,
                             &fake_path_v[0],
-                            &mut *global,
+                            &global_mutex,
                             req,
                             proxy,
+                            &chain,
+                            &clients,
                         )
                         .await);
                     }
                 }
+
+                let mount_v = global.get(&Path::from_str("root->mount")).await.unwrap();
+                let root_v = route::get_mount_roots(&mut *global).await;
+
+                for mount in &mount_v {
+                    let mount_path_v = global
+                        .get(&Path::from_str(&format!("{mount}->path")))
+                        .await
+                        .unwrap();
+                    let dir_v = global
+                        .get(&Path::from_str(&format!("{mount}->dir")))
+                        .await
+                        .unwrap();
+                    if let (Some(mount_path), Some(dir)) = (mount_path_v.first(), dir_v.first()) {
+                        let tail_path = match path.strip_prefix(mount_path.as_str()) {
+                            Some(tail_path)
+                                if tail_path.is_empty() || tail_path.starts_with('/') =>
+                            {
+                                tail_path
+                            }
+                            _ => continue,
+                        };
+                        let dir = dir.clone();
+                        let tail_path = tail_path.to_string();
+                        drop(global);
+                        return Ok(serve_file(&dir, &root_v, &tail_path, req).await);
+                    }
+                }
             }
 
             service.call(req).await
-        })
+        }))
     }
 }
 
@@ -81,11 +137,21 @@ where
 // 1. Middleware initialization, middleware factory gets called with
 //    next service in chain as parameter.
 // 2. Middleware's call method gets called with normal request.
-pub struct Proxy {}
+#[derive(Clone)]
+pub struct Proxy {
+    chain: Arc<proxy::discovery::Chain>,
+    clients: Arc<proxy::client::Clients>,
+}
 
 impl Proxy {
-    pub fn new() -> Self {
-        Self {}
+    /// `discovery_v` are the backends resolving services, see `proxy::discovery::parse`.
+    /// HTTPS upstreams are verified against the CA bundle at `ca` too, or not at all if
+    /// `insecure`.
+    pub fn new(discovery_v: &[String], ca: Option<&str>, insecure: bool) -> err::Result<Self> {
+        Ok(Self {
+            chain: Arc::new(proxy::discovery::Chain::parse_list(discovery_v)),
+            clients: Arc::new(proxy::client::Clients::new(ca, insecure)?),
+        })
     }
 }
 
@@ -106,6 +172,93 @@ where
     fn new_transform(&self, service: S) -> Self::Future {
         future::ready(Ok(ProxyMiddleware {
             service: Arc::new(service),
+            chain: self.chain.clone(),
+            clients: self.clients.clone(),
         }))
     }
 }
+
+// Private
+/// Serve the file at `tail_path` in `dir`, `index.html` for directories. `dir` must be in any of
+/// `root_v`, and symlinks must not lead out of it.
+async fn serve_file(
+    dir: &str,
+    root_v: &[String],
+    tail_path: &str,
+    req: ServiceRequest,
+) -> ServiceResponse<BoxBody> {
+    let real_dir = match route::resolve_dir(dir, root_v) {
+        Ok(real_dir) => real_dir,
+        Err(e) => {
+            log::warn!("{e}\nwhen serve_file");
+            return req.into_response(HttpResponse::NotFound().finish());
+        }
+    };
+    let mut file_path = real_dir.clone();
+    for segment in tail_path.split('/').filter(|s| !s.is_empty() && *s != ".") {
+        // Never leave the directory.
+        if segment == ".." || segment.contains('\\') {
+            return req.into_response(HttpResponse::NotFound().finish());
+        }
+        file_path.push(segment);
+    }
+    if file_path.is_dir() {
+        file_path.push("index.html");
+    }
+    let file_path = match std::fs::canonicalize(&file_path) {
+        Ok(file_path) if file_path.starts_with(&real_dir) => file_path,
+        _ => return req.into_response(HttpResponse::NotFound().finish()),
+    };
+    match NamedFile::open_async(&file_path).await {
+        Ok(file) => {
+            let (req, _) = req.into_parts();
+            let res = file.into_response(&req);
+            ServiceResponse::new(req, res)
+        }
+        Err(_) => req.into_response(HttpResponse::NotFound().finish()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{http::StatusCode, test::TestRequest};
+
+    use super::serve_file;
+
+    #[test]
+    fn test_serve_file() {
+        let root = std::env::temp_dir().join(format!("light-test-serve-{}", std::process::id()));
+        let www = root.join("www");
+        std::fs::create_dir_all(&www).unwrap();
+        std::fs::write(www.join("index.html"), "index").unwrap();
+        std::fs::write(root.join("secret"), "secret").unwrap();
+        let link = www.join("secret");
+        let _ = std::fs::remove_file(&link);
+        std::os::unix::fs::symlink(root.join("secret"), &link).unwrap();
+        let www_dir = www.to_string_lossy().to_string();
+        let root_dir = root.to_string_lossy().to_string();
+        let root_v = vec![www_dir.clone()];
+
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+            .block_on(async {
+                let req = || TestRequest::default().to_srv_request();
+                let res = serve_file(&www_dir, &root_v, "/", req()).await;
+                assert_eq!(res.status(), StatusCode::OK);
+                assert_eq!(&actix_web::test::read_body(res).await[..], b"index");
+
+                // Neither up nor through symlinks out of the directory.
+                let res = serve_file(&www_dir, &root_v, "/../secret", req()).await;
+                assert_eq!(res.status(), StatusCode::NOT_FOUND);
+                let res = serve_file(&www_dir, &root_v, "/secret", req()).await;
+                assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+                // Nor out of the roots.
+                let res = serve_file(&root_dir, &root_v, "/secret", req()).await;
+                assert_eq!(res.status(), StatusCode::NOT_FOUND);
+            });
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
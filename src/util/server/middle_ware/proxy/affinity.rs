@@ -0,0 +1,140 @@
+//! Session affinity for proxied services.
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use actix_web::{
+    http::header::{HeaderValue, SET_COOKIE},
+    HttpRequest, HttpResponse,
+};
+
+// Public
+/// Name of the cookie issued by light to pin a client to an instance.
+pub const COOKIE_NAME: &str = "light_affinity";
+
+/// How a client is pinned to an instance, stored in `$->$:proxy->sticky`.
+///
+/// - unset: no affinity, the first instance is used.
+/// - `light`: light issues its own cookie holding the instance id.
+/// - `cookie:{name}`: the value of an existing cookie is hashed onto an instance.
+/// - `header:{name}`: the value of an existing header is hashed onto an instance.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Affinity {
+    Off,
+    Light,
+    Cookie(String),
+    Header(String),
+}
+
+impl Affinity {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s.split_once(':') {
+            None if s == "light" => Some(Self::Light),
+            Some(("cookie", name)) if !name.is_empty() => Some(Self::Cookie(name.to_string())),
+            Some(("header", name)) if !name.is_empty() => Some(Self::Header(name.to_string())),
+            _ => None,
+        }
+    }
+
+    /// Get the affinity key carried by the request.
+    pub fn key(&self, req: &HttpRequest) -> Option<String> {
+        match self {
+            Self::Off => None,
+            Self::Light => req.cookie(COOKIE_NAME).map(|c| c.value().to_string()),
+            Self::Cookie(name) => req.cookie(name).map(|c| c.value().to_string()),
+            Self::Header(name) => req
+                .headers()
+                .get(name.as_str())
+                .and_then(|v| v.to_str().ok())
+                .map(|v| v.to_string()),
+        }
+    }
+
+    /// Pick the index of the instance for the key out of `id_v`.
+    pub fn pick(&self, id_v: &[String], key: Option<&str>) -> Option<usize> {
+        if id_v.is_empty() {
+            return None;
+        }
+        match (self, key) {
+            (Self::Off, _) => Some(0),
+            (Self::Light, Some(key)) => id_v
+                .iter()
+                .position(|id| id == key)
+                .or_else(|| Some(spread(id_v.len()))),
+            (Self::Light, None) => Some(spread(id_v.len())),
+            (_, Some(key)) => Some(hash(key) as usize % id_v.len()),
+            (_, None) => Some(spread(id_v.len())),
+        }
+    }
+
+    /// Pin the client to the instance if it is not pinned to it yet.
+    pub fn pin(&self, res: &mut HttpResponse, key: Option<&str>, id: &str, fake_path: &str) {
+        if *self != Self::Light || key == Some(id) {
+            return;
+        }
+        let path = if fake_path.is_empty() { "/" } else { fake_path };
+        match HeaderValue::from_str(&format!(
+            "{COOKIE_NAME}={id}; Path={path}; HttpOnly; SameSite=Lax"
+        )) {
+            Ok(value) => {
+                res.headers_mut().append(SET_COOKIE, value);
+            }
+            Err(e) => log::warn!("{e}\nwhen pin"),
+        }
+    }
+}
+
+/// Id of an instance, safe to hand out to clients.
+pub fn instance_id(uri: &str) -> String {
+    format!("{:016x}", hash(uri))
+}
+
+// Private
+fn hash(s: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    s.hash(&mut hasher);
+    hasher.finish()
+}
+
+fn spread(len: usize) -> usize {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.subsec_nanos() as usize)
+        .unwrap_or(0)
+        % len
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Affinity;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(Affinity::parse("light"), Some(Affinity::Light));
+        assert_eq!(
+            Affinity::parse("cookie:SESSION"),
+            Some(Affinity::Cookie("SESSION".to_string()))
+        );
+        assert_eq!(
+            Affinity::parse("header:X-User"),
+            Some(Affinity::Header("X-User".to_string()))
+        );
+        assert_eq!(Affinity::parse("cookie:"), None);
+        assert_eq!(Affinity::parse("other"), None);
+    }
+
+    #[test]
+    fn test_pick() {
+        let id_v = vec!["a".to_string(), "b".to_string(), "c".to_string()];
+
+        assert_eq!(Affinity::Light.pick(&id_v, Some("b")), Some(1));
+        let header = Affinity::Header("X-User".to_string());
+        let first = header.pick(&id_v, Some("user")).unwrap();
+        for _ in 0..8 {
+            assert_eq!(header.pick(&id_v, Some("user")), Some(first));
+        }
+        assert_eq!(header.pick(&[], Some("user")), None);
+    }
+}
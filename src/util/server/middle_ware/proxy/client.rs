@@ -0,0 +1,132 @@
+//! Clients reaching upstreams and moon servers.
+//!
+//! What to trust is up to this light, never to the instances: the CA bundle and whether to
+//! verify at all come from its config. Clients are built once and shared, one for each server
+//! name an instance is reached by over TLS.
+use std::{collections::HashMap, sync::Mutex};
+
+use crate::err;
+
+use super::inner::Instance;
+
+// Public
+pub struct Clients {
+    ca_v: Vec<reqwest::Certificate>,
+    insecure: bool,
+    client: reqwest::Client,
+    /// Clients resolving a server name to an address, by `{sni}@{ip}:{port}`.
+    client_mp: Mutex<HashMap<String, reqwest::Client>>,
+}
+
+impl Clients {
+    /// Trust the CAs in the PEM bundle at `ca` besides the system's, verify nothing if
+    /// `insecure`.
+    pub fn new(ca: Option<&str>, insecure: bool) -> err::Result<Self> {
+        let ca_v = match ca {
+            Some(ca) => {
+                let pem = std::fs::read_to_string(ca).map_err(err::map_io_err)?;
+                split_pem(&pem)
+                    .iter()
+                    .map(|cert| {
+                        reqwest::Certificate::from_pem(cert.as_bytes())
+                            .map_err(|e| err::Error::Other(format!("{e}\nwhen Clients::new")))
+                    })
+                    .collect::<err::Result<Vec<_>>>()?
+            }
+            None => Vec::new(),
+        };
+        let client = build(&ca_v, insecure, None)?;
+        Ok(Self {
+            ca_v,
+            insecure,
+            client,
+            client_mp: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Client for anything but instances with a server name.
+    pub fn plain(&self) -> reqwest::Client {
+        self.client.clone()
+    }
+
+    pub fn get(&self, instance: &Instance) -> err::Result<reqwest::Client> {
+        let (sni, addr) = match (
+            &instance.sni,
+            instance.ip.parse::<std::net::IpAddr>(),
+            instance.port.parse::<u16>(),
+        ) {
+            (Some(sni), Ok(ip), Ok(port)) if instance.scheme == "https" => {
+                (sni, std::net::SocketAddr::new(ip, port))
+            }
+            _ => return Ok(self.plain()),
+        };
+        let key = format!("{sni}@{addr}");
+        let mut client_mp = self.client_mp.lock().unwrap();
+        if let Some(client) = client_mp.get(&key) {
+            return Ok(client.clone());
+        }
+        let client = build(&self.ca_v, self.insecure, Some((sni.as_str(), addr)))?;
+        client_mp.insert(key, client.clone());
+        Ok(client)
+    }
+}
+
+// Private
+/// Connect to `addr` while speaking TLS as `sni` if given. Redirects are never followed.
+fn build(
+    ca_v: &[reqwest::Certificate],
+    insecure: bool,
+    resolve: Option<(&str, std::net::SocketAddr)>,
+) -> err::Result<reqwest::Client> {
+    let mut builder = reqwest::Client::builder().redirect(reqwest::redirect::Policy::none());
+    for ca in ca_v {
+        builder = builder.add_root_certificate(ca.clone());
+    }
+    if insecure {
+        builder = builder.danger_accept_invalid_certs(true);
+    }
+    if let Some((sni, addr)) = resolve {
+        builder = builder.resolve(sni, addr);
+    }
+    builder
+        .build()
+        .map_err(|e| err::Error::Other(format!("{e}\nwhen build")))
+}
+
+/// Split a PEM bundle into its certificates.
+fn split_pem(pem: &str) -> Vec<String> {
+    const END: &str = "-----END CERTIFICATE-----";
+    pem.split_inclusive(END)
+        .filter(|cert| cert.contains(END))
+        .map(|cert| cert.trim().to_string())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Clients;
+    use crate::util::registry::WebServer;
+
+    #[test]
+    fn test_get() {
+        let clients = Clients::new(None, false).unwrap();
+        let instance = WebServer {
+            name: "api".to_string(),
+            scheme: "https".to_string(),
+            ip: "10.0.0.2".to_string(),
+            port: "8443".to_string(),
+            sni: Some("api.example.com".to_string()),
+            ..Default::default()
+        };
+        clients.get(&instance).unwrap();
+        clients.get(&instance).unwrap();
+        assert_eq!(clients.client_mp.lock().unwrap().len(), 1);
+
+        let instance = WebServer {
+            scheme: "http".to_string(),
+            ..instance
+        };
+        clients.get(&instance).unwrap();
+        assert_eq!(clients.client_mp.lock().unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,175 @@
+//! Resolve by DNS, A and AAAA or SRV records, cached as long as their TTLs.
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use edge_lib::util::data::MemDataManager;
+use futures_util::future::LocalBoxFuture;
+use hickory_resolver::{error::ResolveErrorKind, TokioAsyncResolver};
+
+use super::{Discovery, Instance};
+use crate::{err, util::registry};
+
+// Public
+pub struct Dns {
+    /// Host to look up, `{name}` in it replaced by the name.
+    host: String,
+    /// Port of the instances if resolving A and AAAA records, `None` for SRV records.
+    port: Option<String>,
+    resolver: Mutex<Option<TokioAsyncResolver>>,
+}
+
+impl Dns {
+    /// Resolve A and AAAA records of `{host}[:{port}]`, port 80 by default.
+    pub fn a(s: &str) -> Self {
+        let (host, port) = match s.rsplit_once(':') {
+            Some((host, port)) if port.parse::<u16>().is_ok() => (host, port),
+            _ => (s, "80"),
+        };
+        Self {
+            host: host.to_string(),
+            port: Some(port.to_string()),
+            resolver: Mutex::new(None),
+        }
+    }
+
+    /// Resolve SRV records of `host`.
+    pub fn srv(host: &str) -> Self {
+        Self {
+            host: host.to_string(),
+            port: None,
+            resolver: Mutex::new(None),
+        }
+    }
+
+    fn resolver(&self) -> err::Result<TokioAsyncResolver> {
+        let mut resolver = self.resolver.lock().unwrap();
+        if resolver.is_none() {
+            *resolver = Some(
+                TokioAsyncResolver::tokio_from_system_conf()
+                    .map_err(|e| err::Error::Other(format!("{e}\nwhen resolver")))?,
+            );
+        }
+        Ok(resolver.clone().unwrap())
+    }
+
+    async fn resolve_a(
+        &self,
+        resolver: &TokioAsyncResolver,
+        name: &str,
+        host: &str,
+        port: &str,
+    ) -> err::Result<Vec<Instance>> {
+        let lookup = match resolver.lookup_ip(host).await {
+            Ok(lookup) => lookup,
+            Err(e) if matches!(e.kind(), ResolveErrorKind::NoRecordsFound { .. }) => {
+                return Ok(Vec::new())
+            }
+            Err(e) => return Err(err::Error::Other(format!("{e}\nwhen resolve_a"))),
+        };
+        let expire = expire(lookup.valid_until());
+        Ok(lookup
+            .iter()
+            .map(|ip| Instance {
+                name: name.to_string(),
+                scheme: "http".to_string(),
+                ip: ip.to_string(),
+                port: port.to_string(),
+                expire: Some(expire),
+                ..Default::default()
+            })
+            .collect())
+    }
+
+    async fn resolve_srv(
+        &self,
+        resolver: &TokioAsyncResolver,
+        name: &str,
+        host: &str,
+    ) -> err::Result<Vec<Instance>> {
+        let lookup = match resolver.srv_lookup(host).await {
+            Ok(lookup) => lookup,
+            Err(e) if matches!(e.kind(), ResolveErrorKind::NoRecordsFound { .. }) => {
+                return Ok(Vec::new())
+            }
+            Err(e) => return Err(err::Error::Other(format!("{e}\nwhen resolve_srv"))),
+        };
+        let expire = expire(lookup.as_lookup().valid_until());
+        // Only the targets of the lowest priority are used, the others are backups.
+        let priority = match lookup.iter().map(|srv| srv.priority()).min() {
+            Some(priority) => priority,
+            None => return Ok(Vec::new()),
+        };
+        let mut instance_v = Vec::new();
+        for srv in lookup.iter().filter(|srv| srv.priority() == priority) {
+            let target = srv.target().to_utf8();
+            let port = srv.port().to_string();
+            for mut instance in self
+                .resolve_a(resolver, name, target.trim_end_matches('.'), &port)
+                .await?
+            {
+                instance.expire = instance.expire.map(|e| e.min(expire));
+                instance
+                    .meta
+                    .insert("weight".to_string(), srv.weight().to_string());
+                instance_v.push(instance);
+            }
+        }
+        Ok(instance_v)
+    }
+}
+
+impl Discovery for Dns {
+    fn resolve<'a>(
+        &'a self,
+        _: &'a tokio::sync::Mutex<MemDataManager>,
+        name: &'a str,
+    ) -> LocalBoxFuture<'a, err::Result<Vec<Instance>>> {
+        Box::pin(async move {
+            let resolver = self.resolver()?;
+            let host = self.host.replace("{name}", name);
+            match &self.port {
+                Some(port) => self.resolve_a(&resolver, name, &host, port).await,
+                None => self.resolve_srv(&resolver, name, &host).await,
+            }
+        })
+    }
+}
+
+// Private
+/// Seconds since the unix epoch when records valid until `instant` expire.
+fn expire(instant: Instant) -> u64 {
+    let ttl = instant
+        .checked_duration_since(Instant::now())
+        .unwrap_or(Duration::ZERO);
+    registry::now() + ttl.as_secs().max(1)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::Dns;
+    use crate::util::registry;
+
+    #[test]
+    fn test_parse() {
+        let dns = Dns::a("{name}.svc.example.com:8080");
+        assert_eq!(dns.host, "{name}.svc.example.com");
+        assert_eq!(dns.port.as_deref(), Some("8080"));
+        let dns = Dns::a("{name}.svc.example.com");
+        assert_eq!(dns.port.as_deref(), Some("80"));
+        let dns = Dns::srv("_{name}._tcp.example.com");
+        assert_eq!(dns.host, "_{name}._tcp.example.com");
+        assert!(dns.port.is_none());
+    }
+
+    #[test]
+    fn test_expire() {
+        let expire = super::expire(Instant::now() + Duration::from_secs(30));
+        assert!(expire >= registry::now() + 29 && expire <= registry::now() + 30);
+        // Records at the end of their TTL are kept for a second.
+        assert!(super::expire(Instant::now()) >= registry::now() + 1);
+    }
+}
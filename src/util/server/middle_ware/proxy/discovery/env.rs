@@ -0,0 +1,64 @@
+//! Resolve by environment variables, like `LIGHT_SERVICE_API=http://10.0.0.2:8080,http://10.0.0.3:8080`.
+use edge_lib::util::data::MemDataManager;
+use futures_util::future::LocalBoxFuture;
+use tokio::sync::Mutex;
+
+use super::{Discovery, Instance};
+use crate::err;
+
+// Public
+pub const DEFAULT_PREFIX: &str = "LIGHT_SERVICE_";
+
+pub struct Env {
+    prefix: String,
+}
+
+impl Env {
+    pub fn new(prefix: &str) -> Self {
+        Self {
+            prefix: prefix.to_string(),
+        }
+    }
+}
+
+impl Discovery for Env {
+    fn resolve<'a>(
+        &'a self,
+        _: &'a Mutex<MemDataManager>,
+        name: &'a str,
+    ) -> LocalBoxFuture<'a, err::Result<Vec<Instance>>> {
+        Box::pin(async move {
+            match std::env::var(variable(&self.prefix, name)) {
+                Ok(value) => Ok(super::parse_upstreams(
+                    name,
+                    std::iter::once(value.as_str()),
+                )),
+                Err(_) => Ok(Vec::new()),
+            }
+        })
+    }
+}
+
+// Private
+/// Name of the variable, `name` in upper case with `-` and `.` as `_`.
+fn variable(prefix: &str, name: &str) -> String {
+    let name = name
+        .chars()
+        .map(|c| match c {
+            '-' | '.' => '_',
+            c => c.to_ascii_uppercase(),
+        })
+        .collect::<String>();
+    format!("{prefix}{name}")
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn test_variable() {
+        assert_eq!(
+            super::variable(super::DEFAULT_PREFIX, "user-api.v2"),
+            "LIGHT_SERVICE_USER_API_V2"
+        );
+    }
+}
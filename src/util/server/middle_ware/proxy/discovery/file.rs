@@ -0,0 +1,106 @@
+//! Resolve by a file mapping names to upstreams, like
+//!
+//! ```json
+//! {"api": ["http://127.0.0.1:8080", "unix:/run/api.sock"]}
+//! ```
+//!
+//! or the same in TOML if the file ends with `.toml`.
+use std::{collections::BTreeMap, sync::Mutex, time::SystemTime};
+
+use edge_lib::util::data::MemDataManager;
+use futures_util::future::LocalBoxFuture;
+
+use super::{Discovery, Instance};
+use crate::err;
+
+// Public
+pub struct File {
+    path: String,
+    /// Modification time of the file when it was loaded, and what it held.
+    loaded: Mutex<Option<(SystemTime, BTreeMap<String, Vec<String>>)>>,
+}
+
+impl File {
+    pub fn new(path: &str) -> Self {
+        Self {
+            path: path.to_string(),
+            loaded: Mutex::new(None),
+        }
+    }
+
+    /// Upstreams of `name`, reloading the file if it changed since it was loaded.
+    fn get(&self, name: &str) -> err::Result<Vec<String>> {
+        let modified = std::fs::metadata(&self.path)
+            .and_then(|metadata| metadata.modified())
+            .map_err(err::map_io_err)?;
+        let mut loaded = self.loaded.lock().unwrap();
+        if loaded.as_ref().map(|(time, _)| *time) != Some(modified) {
+            log::info!("loading {}", self.path);
+            *loaded = Some((modified, self.load()?));
+        }
+        Ok(loaded
+            .as_ref()
+            .and_then(|(_, upstream_mp)| upstream_mp.get(name).cloned())
+            .unwrap_or_default())
+    }
+
+    fn load(&self) -> err::Result<BTreeMap<String, Vec<String>>> {
+        let content = std::fs::read_to_string(&self.path).map_err(err::map_io_err)?;
+        if self.path.ends_with(".toml") {
+            toml::from_str(&content)
+                .map_err(|e| err::Error::Other(format!("{e}\nwhen load {}", self.path)))
+        } else {
+            serde_json::from_str(&content)
+                .map_err(|e| err::Error::Other(format!("{e}\nwhen load {}", self.path)))
+        }
+    }
+}
+
+impl Discovery for File {
+    fn resolve<'a>(
+        &'a self,
+        _: &'a tokio::sync::Mutex<MemDataManager>,
+        name: &'a str,
+    ) -> LocalBoxFuture<'a, err::Result<Vec<Instance>>> {
+        Box::pin(async move {
+            let upstream_v = self.get(name)?;
+            Ok(super::parse_upstreams(
+                name,
+                upstream_v.iter().map(|s| s.as_str()),
+            ))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::File;
+
+    #[test]
+    fn test_get() {
+        let dir = std::env::temp_dir().join(format!("light-test-file-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let path = dir.join("services.json");
+        std::fs::write(
+            &path,
+            r#"{"api": ["http://127.0.0.1:8080", "unix:/run/api.sock"]}"#,
+        )
+        .unwrap();
+        let file = File::new(path.to_str().unwrap());
+        assert_eq!(file.get("api").unwrap().len(), 2);
+        assert!(file.get("web").unwrap().is_empty());
+
+        let path = dir.join("services.toml");
+        std::fs::write(&path, "web = [\"http://127.0.0.1:8081\"]\n").unwrap();
+        let file = File::new(path.to_str().unwrap());
+        assert_eq!(file.get("web").unwrap(), vec!["http://127.0.0.1:8081"]);
+
+        std::fs::write(&path, "web = ").unwrap();
+        assert!(File::new(path.to_str().unwrap()).get("web").is_err());
+        assert!(File::new(dir.join("none.json").to_str().unwrap())
+            .get("api")
+            .is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,285 @@
+//! Resolve the instances of a service from pluggable backends.
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use edge_lib::util::{
+    data::{AsDataManager, MemDataManager},
+    Path,
+};
+use futures_util::future::LocalBoxFuture;
+use tokio::sync::Mutex;
+
+use super::inner::Instance;
+use crate::err;
+
+mod dns;
+mod env;
+mod file;
+mod moon;
+
+// Public
+/// A backend resolving the instances of a service by name.
+pub trait Discovery: Send + Sync {
+    /// Resolve the instances of `name`, empty if the backend doesn't know it.
+    ///
+    /// Instances carry an `expire` if they may change, after which they are resolved again.
+    fn resolve<'a>(
+        &'a self,
+        global: &'a Mutex<MemDataManager>,
+        name: &'a str,
+    ) -> LocalBoxFuture<'a, err::Result<Vec<Instance>>>;
+}
+
+/// Parse a backend, stored in `root->discovery`.
+///
+/// - `moon`: moon servers in `root->moon_server`.
+/// - `file:{path}`: a JSON or TOML file mapping names to upstreams, reloaded when it changes.
+/// - `dns:{host}[:{port}]`: A and AAAA records of `host`, `{name}` in it replaced by the name.
+/// - `srv:{host}`: SRV records of `host`, like `_{name}._tcp.example.com`.
+/// - `env[:{prefix}]`: upstreams in the variable `{prefix}{NAME}`, `LIGHT_SERVICE_` by default.
+pub fn parse(s: &str) -> Option<Box<dyn Discovery>> {
+    let (kind, arg) = match s.split_once(':') {
+        Some((kind, arg)) => (kind, Some(arg)),
+        None => (s, None),
+    };
+    match (kind, arg) {
+        ("moon", None) => Some(Box::new(moon::Moon)),
+        ("file", Some(path)) if !path.is_empty() => Some(Box::new(file::File::new(path))),
+        ("dns", Some(host)) if !host.is_empty() => Some(Box::new(dns::Dns::a(host))),
+        ("srv", Some(host)) if !host.is_empty() => Some(Box::new(dns::Dns::srv(host))),
+        ("env", prefix) => Some(Box::new(env::Env::new(
+            prefix.unwrap_or(env::DEFAULT_PREFIX),
+        ))),
+        _ => None,
+    }
+}
+
+/// Backends tried in order, the first one knowing a name resolves it.
+///
+/// Concurrent lookups of a name share one in flight, and a failed lookup is replayed for
+/// `root->negative_ttl` seconds instead of hitting the backends again. What is resolved is cached
+/// here until it expires, apart from the registry in `root->web_server`.
+#[derive(Default)]
+pub struct Chain {
+    backend_v: Vec<Box<dyn Discovery>>,
+    /// Lookups in flight by name, locked until they finish with the result.
+    flight_mp: std::sync::Mutex<HashMap<String, Arc<Mutex<Option<Flight>>>>>,
+    /// Failed lookups by name, with when they failed.
+    failure_mp: std::sync::Mutex<HashMap<String, (Instant, String)>>,
+    /// Instances resolved last for each name.
+    cache_mp: std::sync::Mutex<HashMap<String, Vec<Instance>>>,
+}
+
+impl Chain {
+    pub fn parse_list(s_v: &[String]) -> Self {
+        let backend_v = s_v
+            .iter()
+            .filter_map(|s| {
+                let backend = parse(s);
+                if backend.is_none() {
+                    log::warn!("unknown discovery: {s}");
+                }
+                backend
+            })
+            .collect();
+        Self {
+            backend_v,
+            ..Default::default()
+        }
+    }
+
+    pub async fn resolve(
+        &self,
+        global: &Mutex<MemDataManager>,
+        name: &str,
+    ) -> err::Result<Vec<Instance>> {
+        let negative_ttl = get_negative_ttl(global).await;
+        if let Some(e) = self.failure(name, negative_ttl) {
+            return Err(err::Error::Other(e));
+        }
+        let flight = self
+            .flight_mp
+            .lock()
+            .unwrap()
+            .entry(name.to_string())
+            .or_default()
+            .clone();
+        let mut done = flight.lock().await;
+        if let Some(rs) = &*done {
+            // It finished while this one waited.
+            return rs.clone().map_err(err::Error::Other);
+        }
+        let rs = self
+            .resolve_once(global, name)
+            .await
+            .map_err(|e| e.to_string());
+        match &rs {
+            Ok(instance_v) => {
+                self.cache_mp
+                    .lock()
+                    .unwrap()
+                    .insert(name.to_string(), instance_v.clone());
+            }
+            Err(e) => {
+                log::warn!("{e}\nwhen resolve");
+                self.failure_mp
+                    .lock()
+                    .unwrap()
+                    .insert(name.to_string(), (Instant::now(), e.clone()));
+            }
+        }
+        *done = Some(rs.clone());
+        // Those waiting share the result, later lookups start a new flight.
+        self.flight_mp.lock().unwrap().remove(name);
+        rs.map_err(err::Error::Other)
+    }
+
+    /// Why `name` failed to resolve within `negative_ttl`, if it did. Older failures are dropped.
+    fn failure(&self, name: &str, negative_ttl: Duration) -> Option<String> {
+        let mut failure_mp = self.failure_mp.lock().unwrap();
+        failure_mp.retain(|_, (done, _)| done.elapsed() < negative_ttl);
+        failure_mp.get(name).map(|(_, e)| e.clone())
+    }
+
+    /// Instances of `name` cached and not expired yet.
+    pub fn cached(&self, name: &str) -> Vec<Instance> {
+        let now = crate::util::registry::now();
+        let mut cache_mp = self.cache_mp.lock().unwrap();
+        let instance_v = match cache_mp.get_mut(name) {
+            Some(instance_v) => instance_v,
+            None => return Vec::new(),
+        };
+        instance_v.retain(|instance| instance.expire.map(|expire| expire >= now).unwrap_or(true));
+        instance_v.clone()
+    }
+
+    /// Forget a cached instance that couldn't be reached.
+    pub fn evict(&self, instance: &Instance) {
+        if let Some(instance_v) = self.cache_mp.lock().unwrap().get_mut(&instance.name) {
+            instance_v.retain(|cached| cached.id() != instance.id());
+        }
+    }
+
+    async fn resolve_once(
+        &self,
+        global: &Mutex<MemDataManager>,
+        name: &str,
+    ) -> err::Result<Vec<Instance>> {
+        let mut e_v = Vec::new();
+        for backend in &self.backend_v {
+            match backend.resolve(global, name).await {
+                Ok(instance_v) if !instance_v.is_empty() => return Ok(instance_v),
+                Ok(_) => (),
+                Err(e) => e_v.push(e.to_string()),
+            }
+        }
+        Err(err::Error::Other(format!(
+            "{name} not resolved: {}",
+            e_v.join("; ")
+        )))
+    }
+}
+
+// Private
+/// Seconds instances of backends without TTLs are cached.
+const DEFAULT_TTL: u64 = 5;
+
+/// Seconds a failed lookup is cached, if `root->negative_ttl` is not set.
+const DEFAULT_NEGATIVE_TTL: u64 = 5;
+
+/// Result of a lookup, shared by those waiting on it.
+type Flight = Result<Vec<Instance>, String>;
+
+async fn get_negative_ttl(global: &Mutex<MemDataManager>) -> Duration {
+    let negative_ttl = global
+        .lock()
+        .await
+        .get(&Path::from_str("root->negative_ttl"))
+        .await
+        .ok()
+        .and_then(|value_v| value_v.first().and_then(|s| s.parse::<u64>().ok()))
+        .unwrap_or(DEFAULT_NEGATIVE_TTL);
+    Duration::from_secs(negative_ttl)
+}
+
+/// Parse upstreams separated by `,` as the instances of `name`.
+fn parse_upstreams<'a>(name: &str, upstream_v: impl Iterator<Item = &'a str>) -> Vec<Instance> {
+    let expire = crate::util::registry::now() + DEFAULT_TTL;
+    upstream_v
+        .flat_map(|s| s.split(','))
+        .map(|s| s.trim())
+        .filter(|s| !s.is_empty())
+        .filter_map(|upstream| Instance::from_upstream(upstream))
+        .map(|instance| Instance {
+            name: name.to_string(),
+            expire: Some(expire),
+            ..instance
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    use edge_lib::util::data::MemDataManager;
+    use futures_util::future::LocalBoxFuture;
+    use tokio::sync::Mutex;
+
+    use super::{Chain, Discovery, Instance};
+    use crate::err;
+
+    /// Knows nobody, slowly, counting its lookups.
+    struct Nobody(Arc<AtomicUsize>);
+
+    impl Discovery for Nobody {
+        fn resolve<'a>(
+            &'a self,
+            _: &'a Mutex<MemDataManager>,
+            _: &'a str,
+        ) -> LocalBoxFuture<'a, err::Result<Vec<Instance>>> {
+            Box::pin(async move {
+                self.0.fetch_add(1, Ordering::SeqCst);
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                Ok(Vec::new())
+            })
+        }
+    }
+
+    #[test]
+    fn test_resolve() {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+            .block_on(async {
+                let global = Mutex::new(MemDataManager::new(None));
+                let count = Arc::new(AtomicUsize::new(0));
+                let chain = Chain {
+                    backend_v: vec![Box::new(Nobody(count.clone()))],
+                    ..Default::default()
+                };
+
+                let (a, b) =
+                    tokio::join!(chain.resolve(&global, "api"), chain.resolve(&global, "api"));
+                assert!(a.is_err() && b.is_err());
+                assert_eq!(count.load(Ordering::SeqCst), 1);
+                // The failure is cached.
+                assert!(chain.resolve(&global, "api").await.is_err());
+                assert_eq!(count.load(Ordering::SeqCst), 1);
+                assert!(chain.resolve(&global, "web").await.is_err());
+                assert_eq!(count.load(Ordering::SeqCst), 2);
+                // Nothing stays in flight.
+                assert!(chain.flight_mp.lock().unwrap().is_empty());
+            })
+    }
+}
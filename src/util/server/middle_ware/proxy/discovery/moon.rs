@@ -0,0 +1,85 @@
+//! Resolve by the registry API of moon servers, or by their edge scripts if they don't serve it.
+use edge_lib::util::{
+    data::{AsDataManager, MemDataManager},
+    rs_2_str, Path,
+};
+use futures_util::future::LocalBoxFuture;
+use tokio::sync::Mutex;
+
+use super::{Discovery, Instance};
+use crate::{
+    err,
+    util::{self, registry},
+};
+
+// Public
+pub struct Moon;
+
+impl Discovery for Moon {
+    fn resolve<'a>(
+        &'a self,
+        global: &'a Mutex<MemDataManager>,
+        name: &'a str,
+    ) -> LocalBoxFuture<'a, err::Result<Vec<Instance>>> {
+        Box::pin(async move {
+            let (moon_server_v, credential) = {
+                let mut global = global.lock().await;
+                let moon_server_v = global
+                    .get(&Path::from_str("root->moon_server"))
+                    .await
+                    .map_err(|e| err::Error::Other(e.message().to_string()))?;
+                (moon_server_v, util::sign::get_credential())
+            };
+            if moon_server_v.is_empty() {
+                return Err(err::Error::Other(format!("no moon_server")));
+            }
+
+            let mut e_v = Vec::new();
+            for moon_server in &moon_server_v {
+                let rs = util::native::http_registry_or_script(
+                    moon_server,
+                    &format!("/registry/lookup/{name}"),
+                    None,
+                    &registry::dump_script(Some(name)),
+                    credential.as_ref(),
+                )
+                .await;
+                // Another moon server may know the name.
+                let instance_v = match rs.map_err(err::map_io_err).and_then(parse_answer) {
+                    Ok(instance_v) => instance_v,
+                    Err(e) => {
+                        log::warn!("{e}\nwhen resolve {name} on {moon_server}");
+                        e_v.push(e.to_string());
+                        continue;
+                    }
+                };
+                log::debug!("web_servers: {instance_v:?}");
+                let now = registry::now();
+                let instance_v = instance_v
+                    .into_iter()
+                    .filter(|instance| instance.name == name)
+                    .filter(|instance| registry::check_web_server(instance).is_ok())
+                    .filter(|instance| instance.expire.map(|expire| expire >= now).unwrap_or(true))
+                    .collect::<Vec<Instance>>();
+                if !instance_v.is_empty() {
+                    return Ok(instance_v);
+                }
+            }
+            if e_v.len() == moon_server_v.len() {
+                return Err(err::Error::Other(e_v.join("; ")));
+            }
+            Ok(Vec::new())
+        })
+    }
+}
+
+// Private
+fn parse_answer(answer: util::native::Answer) -> err::Result<Vec<Instance>> {
+    match answer {
+        util::native::Answer::Registry(text) => serde_json::from_str::<Vec<Instance>>(&text)
+            .map_err(|e| err::Error::Other(format!("{e}\nwhen parse_answer"))),
+        util::native::Answer::Script(rs) => json::parse(&rs_2_str(&rs))
+            .map(|web_server_v| registry::parse_web_servers(&web_server_v))
+            .map_err(|e| err::Error::Other(format!("{e}\nwhen parse_answer"))),
+    }
+}
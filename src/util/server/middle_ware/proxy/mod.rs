@@ -1,75 +1,247 @@
 use actix_web::{
     body::BoxBody,
     dev::{ServiceRequest, ServiceResponse},
+    HttpResponse,
 };
 use edge_lib::util::{
     data::{AsDataManager, MemDataManager},
     Path,
 };
-use reqwest::StatusCode;
+use reqwest::{header::HeaderValue, StatusCode};
+use tokio::sync::Mutex;
+
+use super::super::auth;
+use crate::util::{audit, sign};
+
+mod affinity;
+pub mod client;
+pub mod discovery;
+mod selector;
 
 pub async fn respone(
     path: &str,
     fake_path: &str,
-    global: &mut MemDataManager,
+    global_mutex: &Mutex<MemDataManager>,
     req: ServiceRequest,
     proxy: &str,
+    chain: &discovery::Chain,
+    clients: &client::Clients,
 ) -> ServiceResponse<BoxBody> {
     let tail_path = &path[fake_path.len()..];
     let (req, payload) = req.into_parts();
+    // The graph is only locked while read or written, not while upstreams respond.
+    let mut global = global_mutex.lock().await;
+    let sticky_v = global
+        .get(&Path::from_str(&format!("{proxy}->sticky")))
+        .await
+        .unwrap();
+    let affinity = sticky_v
+        .first()
+        .and_then(|sticky| affinity::Affinity::parse(sticky))
+        .unwrap_or(affinity::Affinity::Off);
+    let key = affinity.key(&req);
+
+    let upstream_v = global
+        .get(&Path::from_str(&format!("{proxy}->upstream")))
+        .await
+        .unwrap();
+    if !upstream_v.is_empty() {
+        drop(global);
+        let req_cell = inner::extract_req(&req, payload).await;
+        // Static upstreams, try them one by one until one responds.
+        let mut instance_v = upstream_v
+            .iter()
+            .filter_map(|upstream| inner::Instance::from_upstream(upstream))
+            .collect::<Vec<inner::Instance>>();
+        while !instance_v.is_empty() {
+            let id_v = instance_v
+                .iter()
+                .map(|instance| instance.id())
+                .collect::<Vec<String>>();
+            let i = affinity.pick(&id_v, key.as_deref()).unwrap();
+            match inner::proxy_to(req_cell.clone(), &instance_v[i], tail_path, clients).await {
+                Ok(mut res) => {
+                    affinity.pin(&mut res, key.as_deref(), &id_v[i], fake_path);
+                    return ServiceResponse::new(req, res);
+                }
+                // Unreachable, try the next one.
+                Err(e) => log::warn!("{e}\nwhen respone"),
+            }
+            instance_v.remove(i);
+        }
+        log::error!("no valid upstream in {proxy}\nwhen respone");
+        return ServiceResponse::new(req, HttpResponse::new(StatusCode::BAD_GATEWAY));
+    }
+
     let name_v = global
         .get(&Path::from_str(&format!("{proxy}->name")))
         .await
         .unwrap();
+    let name = match name_v.first() {
+        Some(name) => name.clone(),
+        None => {
+            log::error!("neither upstream nor name in {proxy}\nwhen respone");
+            return ServiceResponse::new(req, HttpResponse::new(StatusCode::BAD_GATEWAY));
+        }
+    };
+    let select_v = global
+        .get(&Path::from_str(&format!("{proxy}->select")))
+        .await
+        .unwrap();
+    let selector = selector::Selector::parse(select_v.first().map(|s| s.as_str()).unwrap_or(""));
+    let meta = inner::get_own_meta(&mut *global).await;
+
+    let instance_v = match inner::get_instances_from_cache(&mut *global, chain, &name).await {
+        Ok(instance_v) => selector.select(instance_v, &meta),
+        Err(e) => {
+            log::error!("{e}\nwhen respone");
+            return ServiceResponse::new(req, HttpResponse::new(StatusCode::BAD_GATEWAY));
+        }
+    };
+    drop(global);
     let req_cell = inner::extract_req(&req, payload).await;
-    if let Some(uri) = inner::get_uri_from_cache(global, &name_v[0]).await.unwrap() {
-        let res = inner::proxy_fn(req_cell.clone(), format!("{uri}{tail_path}")).await;
-        match res.status() {
-            StatusCode::NOT_FOUND => (),
-            _ => {
+    let id_v = instance_v
+        .iter()
+        .map(|instance| instance.id())
+        .collect::<Vec<String>>();
+    let mut unhealthy = None;
+    if let Some(i) = affinity.pick(&id_v, key.as_deref()) {
+        match inner::proxy_to(req_cell.clone(), &instance_v[i], tail_path, clients).await {
+            Ok(mut res) => {
+                affinity.pin(&mut res, key.as_deref(), &id_v[i], fake_path);
                 return ServiceResponse::new(req, res);
             }
+            Err(e) => {
+                // The pinned instance may be gone, fail over to a new one.
+                log::warn!("{e}\nwhen respone");
+                inner::evict_from_cache(&mut *global_mutex.lock().await, chain, &instance_v[i])
+                    .await;
+                unhealthy = Some(id_v[i].clone());
+            }
+        }
+    }
+    let mut instance_v = match inner::get_instances_from_remote(global_mutex, chain, &name).await {
+        Ok(instance_v) => selector.select(instance_v, &meta),
+        Err(e) => {
+            log::error!("{e}\nwhen respone");
+            return ServiceResponse::new(req, HttpResponse::new(StatusCode::BAD_GATEWAY));
+        }
+    };
+    if instance_v.len() > 1 {
+        instance_v.retain(|instance| Some(instance.id()) != unhealthy);
+    }
+    let id_v = instance_v
+        .iter()
+        .map(|instance| instance.id())
+        .collect::<Vec<String>>();
+    let i = match affinity.pick(&id_v, key.as_deref()) {
+        Some(i) => i,
+        None => {
+            log::error!("no instance of {name} selected\nwhen respone");
+            return ServiceResponse::new(req, HttpResponse::new(StatusCode::BAD_GATEWAY));
+        }
+    };
+    match inner::proxy_to(req_cell, &instance_v[i], tail_path, clients).await {
+        Ok(mut res) => {
+            affinity.pin(&mut res, key.as_deref(), &id_v[i], fake_path);
+            ServiceResponse::new(req, res)
+        }
+        Err(e) => {
+            log::error!("{e}\nwhen respone");
+            inner::evict_from_cache(&mut *global_mutex.lock().await, chain, &instance_v[i]).await;
+            ServiceResponse::new(req, HttpResponse::new(StatusCode::BAD_GATEWAY))
         }
     }
-    let uri = inner::get_uri_from_remote(global, &name_v[0])
-        .await
-        .unwrap();
-    return ServiceResponse::new(
-        req,
-        inner::proxy_fn(req_cell, format!("{uri}{tail_path}")).await,
-    );
 }
 
-pub const MOON_SERVICE_PATH: &str = "/moon_server";
-
+/// Pass the request through to moon servers, trying them in order until one responds.
 pub async fn respone_moon(
     path: &str,
-    dm: &mut MemDataManager,
+    moon_path: &str,
+    global_mutex: &Mutex<MemDataManager>,
     req: ServiceRequest,
+    clients: &client::Clients,
 ) -> ServiceResponse<BoxBody> {
     let (req, payload) = req.into_parts();
-    let req_cell = inner::extract_req(&req, payload).await;
+    let mut dm = global_mutex.lock().await;
+    let auth_v = dm.get(&Path::from_str("root->moon_auth")).await.unwrap();
+    let policy_v = auth::Policy::parse_list(auth_v.first().map(|s| s.as_str()).unwrap_or(""));
+    let scheme_v = dm.get(&Path::from_str("root->scheme")).await.unwrap();
+    let unix = scheme_v.first().map(|s| s.as_str()) == Some("unix");
+    if !auth::allow(&policy_v, &req, unix) {
+        log::warn!(
+            "denied {path} from {:?}\nwhen respone_moon",
+            req.peer_addr()
+        );
+        return ServiceResponse::new(req, HttpResponse::new(StatusCode::FORBIDDEN));
+    }
+
     let moon_server_v = dm.get(&Path::from_str("root->moon_server")).await.unwrap();
-    let uri = &moon_server_v[0];
-    let tail_path = &path[MOON_SERVICE_PATH.len()..];
-    return ServiceResponse::new(
-        req,
-        inner::proxy_fn(req_cell, format!("{uri}{tail_path}")).await,
-    );
+    let credential = sign::get_credential();
+    drop(dm);
+    let mut req_cell = inner::extract_req(&req, payload).await;
+    // Credentials of the caller are for this light, never for moon servers, which are called
+    // as this light instead.
+    for name in [
+        reqwest::header::AUTHORIZATION,
+        reqwest::header::PROXY_AUTHORIZATION,
+        reqwest::header::COOKIE,
+    ] {
+        req_cell.1.remove(name);
+    }
+    let tail_path = &path[moon_path.len()..];
+    if tail_path.ends_with("/execute") {
+        // Scripts passed through change moon servers, audited here as they leave.
+        if let Ok(script) = serde_json::from_slice::<Vec<String>>(&req_cell.3) {
+            audit::identify("passthrough".to_string());
+            audit::record(&script);
+        }
+    }
+    for uri in &moon_server_v {
+        let mut req_cell = req_cell.clone();
+        if let Some(credential) = &credential {
+            // A token is good for one call, so each moon server gets its own.
+            let url = match req_cell.2.as_str() {
+                "" => format!("{uri}{tail_path}"),
+                query => format!("{uri}{tail_path}?{query}"),
+            };
+            match credential.authorization(req_cell.0.as_str(), &url, &req_cell.3) {
+                Ok(authorization) => {
+                    if let Ok(value) = HeaderValue::from_str(&authorization) {
+                        req_cell.1.insert(reqwest::header::AUTHORIZATION, value);
+                    }
+                }
+                Err(e) => log::error!("{e}\nwhen respone_moon"),
+            }
+        }
+        let rs = inner::proxy_fn(clients.plain(), req_cell, format!("{uri}{tail_path}")).await;
+        match rs {
+            Ok(res) => return ServiceResponse::new(req, res),
+            // Unreachable, try the next one.
+            Err(e) => log::warn!("{e}\nwhen respone_moon"),
+        }
+    }
+    log::error!("no moon_server reachable\nwhen respone_moon");
+    ServiceResponse::new(req, HttpResponse::new(StatusCode::BAD_GATEWAY))
 }
 
 mod inner {
+    use std::collections::BTreeMap;
+
     use actix_http::{body::BoxBody, Payload};
-    use actix_web::{http::header::HeaderMap, HttpRequest, HttpResponse};
+    use actix_web::{HttpRequest, HttpResponse};
     use edge_lib::util::{
         data::{AsDataManager, MemDataManager},
-        engine::{AsEdgeEngine, EdgeEngine},
-        rs_2_str, Path,
+        Path,
     };
     use futures_util::TryStreamExt;
     use reqwest::{header::HeaderValue, Method, StatusCode};
+    use tokio::sync::Mutex;
 
-    use crate::{err, util};
+    use crate::{
+        err,
+        util::{audit, registry, transaction},
+    };
 
     pub async fn extract_req(
         req: &HttpRequest,
@@ -106,10 +278,30 @@ mod inner {
         )
     }
 
+    /// Forward the request to the instance, `Err` if it can't be reached. Whatever the instance
+    /// responds, even 404, is `Ok`.
+    pub async fn proxy_to(
+        req: (Method, reqwest::header::HeaderMap, String, bytes::Bytes),
+        instance: &Instance,
+        tail_path: &str,
+        clients: &super::client::Clients,
+    ) -> err::Result<HttpResponse> {
+        if instance.scheme == "unix" {
+            return proxy_unix_fn(req, &instance.ip, format!("{}{tail_path}", instance.path)).await;
+        }
+        proxy_fn(
+            clients.get(instance)?,
+            req,
+            format!("{}{tail_path}", instance.uri()),
+        )
+        .await
+    }
+
     pub async fn proxy_fn(
+        client: reqwest::Client,
         req: (Method, reqwest::header::HeaderMap, String, bytes::Bytes),
         uri: String,
-    ) -> HttpResponse {
+    ) -> err::Result<HttpResponse> {
         let uri = {
             let query = &req.2;
             if query.is_empty() {
@@ -119,142 +311,281 @@ mod inner {
             }
         };
 
-        let client = reqwest::Client::builder()
-            .redirect(reqwest::redirect::Policy::none())
-            .build()
-            .unwrap();
         log::info!("proxy: {} {uri}", req.0.as_str());
 
-        match client
-            .request(req.0, uri)
+        let res = client
+            .request(req.0, &uri)
             .headers(req.1)
             .body(req.3)
             .send()
             .await
-        {
-            Ok(res) => {
-                let status = res.status();
-                let headers = {
-                    let mut headers = HeaderMap::new();
-                    for (name, value) in res.headers() {
-                        headers.insert(name.clone(), value.clone());
-                    }
-                    headers
-                };
-                let body = res.bytes().await.unwrap();
-                let mut res = HttpResponse::new(status);
-                for (name, value) in headers {
-                    res.headers_mut().insert(name.clone(), value.clone());
-                }
-                res = res.set_body(BoxBody::new(body));
-                res
+            .map_err(|e| err::Error::Other(format!("{e}\nwhen proxy_fn {uri}")))?;
+        let status = res.status();
+        let headers = res.headers().clone();
+        let body = res
+            .bytes()
+            .await
+            .map_err(|e| err::Error::Other(format!("{e}\nwhen proxy_fn {uri}")))?;
+        Ok(build_res(status, &headers, body))
+    }
+
+    /// Same as `proxy_fn`, but over the unix socket at `socket`.
+    pub async fn proxy_unix_fn(
+        req: (Method, reqwest::header::HeaderMap, String, bytes::Bytes),
+        socket: &str,
+        path: String,
+    ) -> err::Result<HttpResponse> {
+        let path = {
+            let path = if path.is_empty() {
+                "/".to_string()
+            } else {
+                path
+            };
+            let query = &req.2;
+            if query.is_empty() {
+                path
+            } else {
+                format!("{path}?{query}")
             }
-            Err(e) => {
-                log::error!("{:?}", e);
-                HttpResponse::new(StatusCode::NOT_FOUND)
+        };
+        log::info!("proxy: {} unix:{socket}{path}", req.0.as_str());
+
+        let rs: Result<HttpResponse, Box<dyn std::error::Error>> = async {
+            let stream = tokio::net::UnixStream::connect(socket).await?;
+            let (mut sender, conn) = hyper::client::conn::handshake(stream).await?;
+            tokio::spawn(async move {
+                if let Err(e) = conn.await {
+                    log::warn!("{e}\nwhen proxy_unix_fn");
+                }
+            });
+
+            let mut builder = hyper::Request::builder().method(req.0).uri(path);
+            if let Some(headers) = builder.headers_mut() {
+                *headers = req.1;
             }
+            let res = sender
+                .send_request(builder.body(hyper::Body::from(req.3))?)
+                .await?;
+            let status = res.status();
+            let headers = res.headers().clone();
+            let body = hyper::body::to_bytes(res.into_body()).await?;
+            Ok(build_res(status, &headers, body))
         }
+        .await;
+        rs.map_err(|e| err::Error::Other(format!("{e}\nwhen proxy_unix_fn {socket}")))
     }
 
-    pub async fn get_uri_from_cache(
-        global: &mut MemDataManager,
-        name: &str,
-    ) -> err::Result<Option<String>> {
-        let mut edge_engine = EdgeEngine::new(global);
-        let web_server_v = json::parse(&rs_2_str(
-            &edge_engine
-                .execute_script(&[
-                    format!("$->$:web_server inner root->web_server {name}<-name"),
-                    format!("$->$:web_server->$:ip = $->$:web_server->ip _"),
-                    format!("$->$:web_server->$:port = $->$:web_server->port _"),
-                    format!("$->$:web_server->$:path = $->$:web_server->path _"),
-                    format!("$->$:output dump $->$:web_server $"),
-                ])
-                .await
-                .map_err(|e| err::Error::Other(e.message().to_string()))?,
-        ))
-        .unwrap();
+    fn build_res(
+        status: StatusCode,
+        headers: &reqwest::header::HeaderMap,
+        body: bytes::Bytes,
+    ) -> HttpResponse {
+        let mut res = HttpResponse::new(status);
+        for (name, value) in headers {
+            res.headers_mut().insert(name.clone(), value.clone());
+        }
+        res.set_body(BoxBody::new(body))
+    }
+
+    /// An instance of a service registered in `root->web_server`.
+    pub use crate::util::registry::WebServer as Instance;
+
+    impl Instance {
+        /// Parse an upstream given in the config, `{scheme}://{host}[:{port}][{path}]` or
+        /// `unix:{socket path}`.
+        ///
+        /// Instances with the scheme `unix` hold the socket path in `ip`.
+        pub fn from_upstream(upstream: &str) -> Option<Self> {
+            let instance = parser::parse_upstream(upstream);
+            if instance.is_none() {
+                log::warn!("invalid upstream: {upstream}");
+            }
+            instance
+        }
 
-        if web_server_v.len() == 1 {
-            let (ip, port, path) = (
-                web_server_v[0]["ip"][0].as_str().unwrap(),
-                web_server_v[0]["port"][0].as_str().unwrap(),
-                web_server_v[0]["path"][0].as_str().unwrap(),
-            );
-            return Ok(Some(parser::parse_uri(ip, port, path)));
+        /// Uri requests to the instance are sent to, by its server name if it has one so that
+        /// TLS is spoken as that name.
+        pub fn uri(&self) -> String {
+            if self.scheme == "unix" {
+                return format!("unix:{}", self.ip);
+            }
+            let host = self.sni.as_deref().unwrap_or(&self.ip);
+            parser::parse_uri(&self.scheme, host, &self.port, &self.path)
         }
-        Ok(None)
+
+        /// Keyed on the address of the instance, not its server name, which instances may share.
+        pub fn id(&self) -> String {
+            let addr = if self.scheme == "unix" {
+                format!("unix:{}", self.ip)
+            } else {
+                parser::parse_uri(&self.scheme, &self.ip, &self.port, &self.path)
+            };
+            super::affinity::instance_id(&addr)
+        }
+    }
+
+    /// Metadata of this light, in `root->meta` as `{key}={value}`.
+    pub async fn get_own_meta(global: &mut MemDataManager) -> BTreeMap<String, String> {
+        global
+            .get(&Path::from_str("root->meta"))
+            .await
+            .unwrap_or_default()
+            .iter()
+            .filter_map(|meta| meta.split_once('='))
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect()
     }
 
-    pub async fn get_uri_from_remote(
+    /// Instances of `name` in the registry, and those the discovery backends resolved before.
+    pub async fn get_instances_from_cache(
         global: &mut MemDataManager,
+        chain: &super::discovery::Chain,
         name: &str,
-    ) -> err::Result<String> {
-        let moon_server_v = global
-            .get(&Path::from_str("root->moon_server"))
-            .await
-            .map_err(|e| err::Error::Other(e.message().to_string()))?;
-        if moon_server_v.is_empty() {
-            return Err(err::Error::Other(format!("no moon_server")));
+    ) -> err::Result<Vec<Instance>> {
+        let mut instance_v = registry::lookup(global, Some(name)).await?;
+        for instance in chain.cached(name) {
+            if !instance_v.iter().any(|known| known.id() == instance.id()) {
+                instance_v.push(instance);
+            }
         }
+        Ok(instance_v)
+    }
 
-        let mut edge_engine = EdgeEngine::new(global);
-
-        for moon_server in &moon_server_v {
-            let rs = util::native::http_execute_script(
-                moon_server,
-                &[
-                    format!("$->$:web_server inner root->web_server {name}<-name"),
-                    format!("$->$:web_server->$:ip = $->$:web_server->ip _"),
-                    format!("$->$:web_server->$:port = $->$:web_server->port _"),
-                    format!("$->$:web_server->$:path = $->$:web_server->path _"),
-                    format!("$->$:output dump $->$:web_server $"),
-                ],
-            )
-            .await
-            .map_err(err::map_io_err)?;
-            let web_server_v =
-                json::parse(&rs_2_str(&rs)).map_err(|e| err::Error::Other(e.to_string()))?;
-            log::debug!("web_servers: {web_server_v}");
-            let (ip, port, path) = (
-                web_server_v[0]["$:ip"][0].as_str().unwrap(),
-                web_server_v[0]["$:port"][0].as_str().unwrap(),
-                web_server_v[0]["$:path"][0].as_str().unwrap(),
-            );
-            if let Err(e) = edge_engine
-                .execute_script(&[
-                    format!("$->$:web_server = root->web_server {name}<-name"),
-                    format!("$->$:web_server if $->$:web_server ?"),
-                    format!("$->$:web_server->name = {name} _"),
-                    format!("$->$:web_server->ip = {ip} _"),
-                    format!("$->$:web_server->port = {port} _"),
-                    format!("$->$:web_server->path = {path} _"),
-                    format!("root->web_server distinct root->web_server $->$:web_server"),
-                ])
-                .await
-            {
-                log::warn!("failed to execute1 cache, caused by {e:?}\nwhen get_uri_by_name");
-            }
-            return Ok(parser::parse_uri(ip, port, path));
+    /// Resolve the instances of `name` by the discovery backends, which cache them until they
+    /// expire.
+    pub async fn get_instances_from_remote(
+        global_mutex: &Mutex<MemDataManager>,
+        chain: &super::discovery::Chain,
+        name: &str,
+    ) -> err::Result<Vec<Instance>> {
+        chain.resolve(global_mutex, name).await
+    }
+
+    /// Remove an instance that couldn't be reached from the cache and the registry.
+    pub async fn evict_from_cache(
+        global: &mut MemDataManager,
+        chain: &super::discovery::Chain,
+        instance: &Instance,
+    ) {
+        chain.evict(instance);
+        let script = [
+            format!(
+                "$->$:web_server inner root->web_server {}<-name",
+                instance.name
+            ),
+            format!("$->$:web_server inner $->$:web_server {}<-ip", instance.ip),
+            format!(
+                "$->$:web_server inner $->$:web_server {}<-port",
+                instance.port
+            ),
+            format!("root->web_server left root->web_server $->$:web_server"),
+        ];
+        let rs = audit::scope(
+            audit::Actor::internal("proxy"),
+            transaction::execute(global, &script),
+        )
+        .await;
+        if let Err(e) = rs {
+            log::warn!("{e}\nwhen evict_from_cache");
         }
-        Err(err::Error::Other(format!("no uri")))
     }
 
     mod parser {
-        pub fn parse_uri(ip: &str, port: &str, path: &str) -> String {
+        use super::Instance;
+
+        pub fn parse_uri(scheme: &str, ip: &str, port: &str, path: &str) -> String {
+            let default_port = if scheme == "https" { "443" } else { "80" };
             if ip.contains(':') {
-                if port == "80" {
-                    format!("http://[{ip}]{path}")
+                if port == default_port {
+                    format!("{scheme}://[{ip}]{path}")
                 } else {
-                    format!("http://[{ip}]:{port}{path}")
+                    format!("{scheme}://[{ip}]:{port}{path}")
                 }
             } else {
-                if port == "80" {
-                    format!("http://{ip}{path}")
+                if port == default_port {
+                    format!("{scheme}://{ip}{path}")
                 } else {
-                    format!("http://{ip}:{port}{path}")
+                    format!("{scheme}://{ip}:{port}{path}")
                 }
             }
         }
+
+        pub fn parse_upstream(upstream: &str) -> Option<Instance> {
+            if let Some(socket) = upstream.strip_prefix("unix:") {
+                if socket.is_empty() {
+                    return None;
+                }
+                return Some(Instance {
+                    scheme: "unix".to_string(),
+                    ip: socket.to_string(),
+                    port: "_".to_string(),
+                    ..Default::default()
+                });
+            }
+            let url = reqwest::Url::parse(upstream).ok()?;
+            if url.scheme() != "http" && url.scheme() != "https" {
+                return None;
+            }
+            Some(Instance {
+                scheme: url.scheme().to_string(),
+                ip: url
+                    .host_str()?
+                    .trim_start_matches('[')
+                    .trim_end_matches(']')
+                    .to_string(),
+                port: url.port_or_known_default()?.to_string(),
+                path: url.path().trim_end_matches('/').to_string(),
+                ..Default::default()
+            })
+        }
+
+        #[cfg(test)]
+        mod tests {
+            #[test]
+            fn test_parse_uri() {
+                assert_eq!(
+                    super::parse_uri("http", "::1", "80", "/a"),
+                    "http://[::1]/a"
+                );
+                assert_eq!(
+                    super::parse_uri("https", "example.com", "443", "/a"),
+                    "https://example.com/a"
+                );
+                assert_eq!(
+                    super::parse_uri("https", "127.0.0.1", "80", "/a"),
+                    "https://127.0.0.1:80/a"
+                );
+            }
+
+            #[test]
+            fn test_parse_upstream() {
+                let instance = super::parse_upstream("https://[::1]:8443/api/").unwrap();
+                assert_eq!(instance.uri(), "https://[::1]:8443/api");
+                let instance = super::parse_upstream("http://example.com").unwrap();
+                assert_eq!(instance.uri(), "http://example.com");
+                let instance = super::parse_upstream("unix:/run/api.sock").unwrap();
+                assert_eq!(instance.scheme, "unix");
+                assert_eq!(instance.ip, "/run/api.sock");
+                assert!(super::parse_upstream("unix:").is_none());
+                assert!(super::parse_upstream("api").is_none());
+            }
+
+            #[test]
+            fn test_id() {
+                let instance = super::Instance {
+                    scheme: "https".to_string(),
+                    ip: "10.0.0.2".to_string(),
+                    port: "443".to_string(),
+                    sni: Some("api.example.com".to_string()),
+                    ..Default::default()
+                };
+                let other = super::Instance {
+                    ip: "10.0.0.3".to_string(),
+                    ..instance.clone()
+                };
+                assert_eq!(instance.uri(), other.uri());
+                assert_ne!(instance.id(), other.id());
+            }
+        }
     }
 }
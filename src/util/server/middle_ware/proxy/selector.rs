@@ -0,0 +1,101 @@
+//! Select a subset of the instances of a service by their metadata.
+use std::collections::BTreeMap;
+
+use super::inner::Instance;
+
+// Public
+/// A selector, stored in `$->$:proxy->select` as conditions separated by `,`.
+///
+/// - `tag:{tag}`: instances with the tag.
+/// - `{key}={value}`: instances with the metadata.
+/// - `prefer:{key}`: instances with the same metadata as this light, if there are any.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Selector {
+    tag_v: Vec<String>,
+    meta_v: Vec<(String, String)>,
+    prefer_v: Vec<String>,
+}
+
+impl Selector {
+    pub fn parse(s: &str) -> Self {
+        let mut selector = Self::default();
+        for condition in s.split(',').map(|s| s.trim()).filter(|s| !s.is_empty()) {
+            if let Some(tag) = condition.strip_prefix("tag:") {
+                selector.tag_v.push(tag.to_string());
+            } else if let Some(key) = condition.strip_prefix("prefer:") {
+                selector.prefer_v.push(key.to_string());
+            } else if let Some((key, value)) = condition.split_once('=') {
+                selector.meta_v.push((key.to_string(), value.to_string()));
+            } else {
+                log::warn!("invalid condition: {condition}");
+            }
+        }
+        selector
+    }
+
+    pub fn select(
+        &self,
+        mut instance_v: Vec<Instance>,
+        meta: &BTreeMap<String, String>,
+    ) -> Vec<Instance> {
+        instance_v.retain(|instance| {
+            self.tag_v.iter().all(|tag| instance.tags.contains(tag))
+                && self
+                    .meta_v
+                    .iter()
+                    .all(|(key, value)| instance.meta.get(key) == Some(value))
+        });
+        for key in &self.prefer_v {
+            let value = match meta.get(key) {
+                Some(value) => value,
+                None => continue,
+            };
+            if instance_v
+                .iter()
+                .any(|instance| instance.meta.get(key) == Some(value))
+            {
+                instance_v.retain(|instance| instance.meta.get(key) == Some(value));
+            }
+        }
+        instance_v
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::{Instance, Selector};
+
+    fn instance(ip: &str, tag_v: &[&str], meta_v: &[(&str, &str)]) -> Instance {
+        Instance {
+            name: "api".to_string(),
+            ip: ip.to_string(),
+            tags: tag_v.iter().map(|tag| tag.to_string()).collect(),
+            meta: meta_v
+                .iter()
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_select() {
+        let instance_v = vec![
+            instance("a", &["v2"], &[("zone", "east")]),
+            instance("b", &["v2"], &[("zone", "west")]),
+            instance("c", &["v1"], &[("zone", "west")]),
+        ];
+        let meta = BTreeMap::from([("zone".to_string(), "west".to_string())]);
+
+        let selected = Selector::parse("tag:v2").select(instance_v.clone(), &meta);
+        assert_eq!(selected.len(), 2);
+        let selected = Selector::parse("tag:v2,prefer:zone").select(instance_v.clone(), &meta);
+        assert_eq!(selected.len(), 1);
+        assert_eq!(selected[0].ip, "b");
+        let selected = Selector::parse("zone=east,prefer:zone").select(instance_v, &meta);
+        assert_eq!(selected.len(), 1);
+        assert_eq!(selected[0].ip, "a");
+    }
+}
@@ -1,35 +1,1016 @@
-use std::sync::Arc;
+use std::{collections::BTreeMap, panic::AssertUnwindSafe, sync::Arc, time::Duration};
 
 use actix_files::{Files, NamedFile};
 use actix_web::{
     dev::{HttpServiceFactory, ServiceRequest, ServiceResponse},
-    web, HttpResponse, Responder,
+    http::header::{AUTHORIZATION, HOST},
+    web, HttpRequest, HttpResponse, Responder,
 };
 use edge_lib::util::{
-    data::MemDataManager,
-    engine::{AsEdgeEngine, EdgeEngine},
+    data::{AsDataManager, MemDataManager},
+    Path,
 };
+use futures_util::FutureExt;
 use tokio::sync::Mutex;
 
+use super::auth::{self, Caller, Scope};
+use crate::{
+    err,
+    util::{audit, export, gossip, registry, route, sign, store, subscription, transaction},
+};
+
+/// Body of the responses of `/execute` on failure.
+#[derive(serde::Serialize)]
+struct ExecuteError {
+    error: String,
+    /// Index of the line of the script that failed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    line: Option<usize>,
+    /// Id of an internal failure, to find it in the log.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    id: Option<String>,
+}
+
+impl ExecuteError {
+    fn new(error: impl ToString) -> Self {
+        Self {
+            error: error.to_string(),
+            line: None,
+            id: None,
+        }
+    }
+
+    fn line(mut self, line: usize) -> Self {
+        self.line = Some(line);
+        self
+    }
+
+    /// A failure of a script, at the line it failed at if known.
+    fn script(e: err::Error) -> Self {
+        Self {
+            line: e.line(),
+            ..Self::new(e)
+        }
+    }
+
+    /// Log the failure with a new id, the caller only gets the id.
+    fn internal(e: impl std::fmt::Display) -> Self {
+        let id = sign::nonce();
+        log::error!("{id}: {e}\nwhen execute");
+        Self {
+            error: "internal error".to_string(),
+            line: None,
+            id: Some(id),
+        }
+    }
+
+    /// A panic caught from the engine.
+    fn panic(panic: Box<dyn std::any::Any + Send>) -> Self {
+        let e = panic
+            .downcast_ref::<String>()
+            .map(|s| s.as_str())
+            .or_else(|| panic.downcast_ref::<&str>().copied())
+            .unwrap_or("panic");
+        Self::internal(e)
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct ExecuteQuery {
+    /// Roll back what the script wrote if it fails. Default: true
+    atomic: Option<bool>,
+    /// Report what the script would change, then roll it back. Default: false
+    dry_run: Option<bool>,
+}
+
 #[actix_web::post("/execute")]
 async fn execute(
+    req: HttpRequest,
     global_mutex: web::Data<Arc<Mutex<MemDataManager>>>,
+    verifier: web::Data<sign::Verifier>,
+    store: Option<web::Data<Arc<store::Store>>>,
+    query: web::Query<ExecuteQuery>,
     script: String,
 ) -> impl Responder {
     let mut global = global_mutex.lock().await;
-    let mut edge_engine = EdgeEngine::new(&mut *global);
-    let rs = edge_engine
-        .execute_script(&serde_json::from_str::<'_, Vec<String>>(&script).unwrap())
-        .await
-        .unwrap();
+    let caller = match authenticate(&req, &mut *global, &verifier, script.as_bytes()).await {
+        Ok(caller) => caller,
+        Err(res) => return res,
+    };
+    let script = match serde_json::from_str::<'_, Vec<String>>(&script) {
+        Ok(script) => script,
+        Err(e) => return HttpResponse::BadRequest().json(ExecuteError::new(e)),
+    };
+    if let Err(e) = check_lines(&script) {
+        return HttpResponse::BadRequest().json(e);
+    }
+    match &caller {
+        Caller::Anonymous => {
+            if !auth::is_open(&mut *global).await {
+                log::warn!(
+                    "denied unsigned script from {:?}\nwhen execute",
+                    req.peer_addr()
+                );
+                return HttpResponse::Unauthorized().json(ExecuteError::new("signature required"));
+            }
+        }
+        Caller::Admin(id, scope_v) => {
+            if let Err(e) = auth::check_script(scope_v, &script) {
+                log::warn!("denied script of {id}: {e}\nwhen execute");
+                return HttpResponse::Forbidden().json(ExecuteError::script(e));
+            }
+        }
+        Caller::Service(name) => {
+            if let Err(e) = registry::check_script(name, &script) {
+                return HttpResponse::Forbidden().json(ExecuteError::script(e));
+            }
+        }
+    }
+    // A panic of the engine must not take the worker down with the connection.
+    if query.dry_run.unwrap_or(false) {
+        let rs = AssertUnwindSafe(transaction::dry_run(&mut *global, &script))
+            .catch_unwind()
+            .await;
+        return match rs {
+            Ok(Ok(dry_run)) => HttpResponse::Ok().json(dry_run),
+            Ok(Err(e)) => HttpResponse::BadRequest().json(ExecuteError::script(e)),
+            Err(panic) => HttpResponse::InternalServerError().json(ExecuteError::panic(panic)),
+        };
+    }
+    // What scoped scripts really wrote is checked before it is kept, so they run atomically.
+    let scope_v = match &caller {
+        Caller::Admin(_, scope_v) => scope_v.clone(),
+        _ => vec![Scope::All],
+    };
+    let atomic = query.atomic.unwrap_or(true);
+    if !atomic && !scope_v.contains(&Scope::All) {
+        return HttpResponse::BadRequest()
+            .json(ExecuteError::new("scripts of scoped admins run atomically"));
+    }
+    let rs = if atomic {
+        AssertUnwindSafe(transaction::execute_scoped(&mut *global, &script, &scope_v))
+            .catch_unwind()
+            .await
+    } else {
+        // What the script wrote before failing stays.
+        AssertUnwindSafe(transaction::execute_partial(&mut *global, &script))
+            .catch_unwind()
+            .await
+    };
+    // Logged unless rolled back, a failure here only loses the script on restart.
+    let logged = match &rs {
+        Ok(Ok(_)) => true,
+        Ok(Err(_)) => !atomic,
+        Err(_) => false,
+    };
+    if let (true, Some(store)) = (logged, &store) {
+        if let Err(e) = store.append(&script) {
+            log::error!("{e}\nwhen execute");
+        }
+    }
+    match rs {
+        Ok(Ok(rs)) => match serde_json::to_string(&rs) {
+            Ok(body) => HttpResponse::Ok()
+                .content_type("application/json")
+                .body(body),
+            Err(e) => HttpResponse::InternalServerError().json(ExecuteError::internal(e)),
+        },
+        Ok(Err(e)) => HttpResponse::BadRequest().json(ExecuteError::script(e)),
+        Err(panic) => HttpResponse::InternalServerError().json(ExecuteError::panic(panic)),
+    }
+}
+
+/// Each line of a script is `{target} {function} {input} {input1}`, with a target in `root` or `$`.
+fn check_lines(script: &[String]) -> Result<(), ExecuteError> {
+    for (i, line) in script.iter().enumerate() {
+        let token_v = line.split_whitespace().collect::<Vec<&str>>();
+        if token_v.len() != 4 {
+            return Err(ExecuteError::new(format!(
+                "expected 4 tokens, got {}: {line}",
+                token_v.len()
+            ))
+            .line(i));
+        }
+        match token_v[0].split("->").next() {
+            Some("root") | Some("$") => (),
+            _ => return Err(ExecuteError::new(format!("target not in root or $: {line}")).line(i)),
+        }
+    }
+    Ok(())
+}
+
+#[derive(serde::Deserialize)]
+struct RegisterRequest {
+    #[serde(flatten)]
+    web_server: registry::WebServer,
+    /// Seconds, `root->lease` if not given.
+    lease: Option<u64>,
+}
+
+#[derive(serde::Deserialize)]
+struct InstanceRequest {
+    name: String,
+    ip: String,
+    port: String,
+    lease: Option<u64>,
+}
+
+#[actix_web::post("/registry/register")]
+async fn registry_register(
+    req: HttpRequest,
+    global_mutex: web::Data<Arc<Mutex<MemDataManager>>>,
+    verifier: web::Data<sign::Verifier>,
+    body: web::Bytes,
+) -> impl Responder {
+    let mut global = global_mutex.lock().await;
+    let (request, caller) =
+        match authenticate_json::<RegisterRequest>(&req, &mut *global, &verifier, &body).await {
+            Ok(rs) => rs,
+            Err(res) => return res,
+        };
+    if let Err(res) = check_name(&caller, &request.web_server.name) {
+        return res;
+    }
+    let lease = match request.lease {
+        Some(lease) => lease,
+        None => registry::get_lease(&mut *global).await,
+    };
+    match registry::register(&mut *global, request.web_server, lease).await {
+        Ok(web_server) => HttpResponse::Ok().json(web_server),
+        Err(e) => HttpResponse::BadRequest().body(e.to_string()),
+    }
+}
+
+#[actix_web::post("/registry/renew")]
+async fn registry_renew(
+    req: HttpRequest,
+    global_mutex: web::Data<Arc<Mutex<MemDataManager>>>,
+    verifier: web::Data<sign::Verifier>,
+    body: web::Bytes,
+) -> impl Responder {
+    let mut global = global_mutex.lock().await;
+    let (request, caller) =
+        match authenticate_json::<InstanceRequest>(&req, &mut *global, &verifier, &body).await {
+            Ok(rs) => rs,
+            Err(res) => return res,
+        };
+    if let Err(res) = check_name(&caller, &request.name) {
+        return res;
+    }
+    let lease = match request.lease {
+        Some(lease) => lease,
+        None => registry::get_lease(&mut *global).await,
+    };
+    match registry::renew(
+        &mut *global,
+        &request.name,
+        &request.ip,
+        &request.port,
+        lease,
+    )
+    .await
+    {
+        Ok(Some(expire)) => HttpResponse::Ok().json(serde_json::json!({ "expire": expire })),
+        Ok(None) => HttpResponse::NotFound().finish(),
+        Err(e) => HttpResponse::BadRequest().body(e.to_string()),
+    }
+}
+
+#[actix_web::post("/registry/deregister")]
+async fn registry_deregister(
+    req: HttpRequest,
+    global_mutex: web::Data<Arc<Mutex<MemDataManager>>>,
+    verifier: web::Data<sign::Verifier>,
+    body: web::Bytes,
+) -> impl Responder {
+    let mut global = global_mutex.lock().await;
+    let (request, caller) =
+        match authenticate_json::<InstanceRequest>(&req, &mut *global, &verifier, &body).await {
+            Ok(rs) => rs,
+            Err(res) => return res,
+        };
+    if let Err(res) = check_name(&caller, &request.name) {
+        return res;
+    }
+    match registry::deregister(&mut *global, &request.name, &request.ip, &request.port).await {
+        Ok(()) => HttpResponse::Ok().finish(),
+        Err(e) => HttpResponse::BadRequest().body(e.to_string()),
+    }
+}
+
+#[actix_web::get("/registry/lookup/{name}")]
+async fn registry_lookup(
+    req: HttpRequest,
+    global_mutex: web::Data<Arc<Mutex<MemDataManager>>>,
+    verifier: web::Data<sign::Verifier>,
+    name: web::Path<String>,
+) -> impl Responder {
+    let mut global = global_mutex.lock().await;
+    if let Err(res) = authenticate(&req, &mut *global, &verifier, b"").await {
+        return res;
+    }
+    match registry::lookup(&mut *global, Some(&name)).await {
+        Ok(web_server_v) => HttpResponse::Ok().json(web_server_v),
+        Err(e) => HttpResponse::BadRequest().body(e.to_string()),
+    }
+}
+
+#[actix_web::get("/registry/list")]
+async fn registry_list(
+    req: HttpRequest,
+    global_mutex: web::Data<Arc<Mutex<MemDataManager>>>,
+    verifier: web::Data<sign::Verifier>,
+) -> impl Responder {
+    let mut global = global_mutex.lock().await;
+    if let Err(res) = authenticate(&req, &mut *global, &verifier, b"").await {
+        return res;
+    }
+    match registry::lookup(&mut *global, None).await {
+        Ok(web_server_v) => HttpResponse::Ok().json(web_server_v),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct WatchQuery {
+    #[serde(default)]
+    version: String,
+    /// Seconds to wait for a change. Default: 30
+    timeout: Option<u64>,
+}
+
+/// Long-poll for changes of the registry, 204 if nothing changed before the timeout.
+#[actix_web::get("/registry/watch")]
+async fn registry_watch(
+    req: HttpRequest,
+    global_mutex: web::Data<Arc<Mutex<MemDataManager>>>,
+    verifier: web::Data<sign::Verifier>,
+    query: web::Query<WatchQuery>,
+) -> impl Responder {
+    if let Err(res) = authenticate(&req, &mut *global_mutex.lock().await, &verifier, b"").await {
+        return res;
+    }
+    let timeout = Duration::from_secs(query.timeout.unwrap_or(30).min(60));
+    match registry::watch(&global_mutex, &query.version, timeout).await {
+        Ok(Some(snapshot)) => HttpResponse::Ok().json(snapshot),
+        Ok(None) => HttpResponse::NoContent().finish(),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+/// Merge the digest of a member, reply with the digest of this light, signed by the key of this light
+/// for the call it answers.
+#[actix_web::post("/gossip")]
+async fn gossip(
+    req: HttpRequest,
+    global_mutex: web::Data<Arc<Mutex<MemDataManager>>>,
+    verifier: web::Data<sign::Verifier>,
+    cluster: web::Data<Arc<gossip::Cluster>>,
+    body: web::Bytes,
+) -> impl Responder {
+    let (rs, credential) = {
+        let mut global = global_mutex.lock().await;
+        let rs = authenticate_json::<gossip::Digest>(&req, &mut *global, &verifier, &body).await;
+        (rs, sign::get_credential())
+    };
+    let (digest, caller) = match rs {
+        Ok(rs) => rs,
+        Err(res) => return res,
+    };
+    if let Err(e) = cluster.merge(digest, &caller).await {
+        log::warn!("{e}\nwhen gossip");
+        return match caller {
+            Caller::Anonymous => HttpResponse::Unauthorized().body(e.to_string()),
+            _ => HttpResponse::Forbidden().body(e.to_string()),
+        };
+    }
+    let reply = match serde_json::to_string(&cluster.digest()) {
+        Ok(reply) => reply,
+        Err(e) => return HttpResponse::InternalServerError().body(e.to_string()),
+    };
+    let mut res = HttpResponse::Ok();
+    res.content_type("application/json");
+    if let Some(credential) = credential {
+        let htu = req
+            .uri()
+            .path_and_query()
+            .map(|path_and_query| path_and_query.as_str())
+            .unwrap_or(req.path());
+        let host = req
+            .headers()
+            .get(HOST)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or("");
+        match credential.sign("POST", htu, host, reply.as_bytes()) {
+            Ok(authorization) => {
+                res.insert_header((AUTHORIZATION, authorization));
+            }
+            Err(e) => log::error!("{e}\nwhen gossip"),
+        }
+    }
+    res.body(reply)
+}
+
+#[derive(serde::Deserialize)]
+struct PathQuery {
+    path: String,
+}
+
+#[derive(serde::Deserialize)]
+struct MoonServerRequest {
+    uri: String,
+}
+
+#[actix_web::get("/admin/routes")]
+async fn admin_list_routes(
+    req: HttpRequest,
+    global_mutex: web::Data<Arc<Mutex<MemDataManager>>>,
+    verifier: web::Data<sign::Verifier>,
+) -> impl Responder {
+    let mut global = global_mutex.lock().await;
+    if let Err(res) = authorize(&req, &mut *global, &verifier, b"", &[]).await {
+        return res;
+    }
+    match route::list_routes(&mut *global).await {
+        Ok(route_v) => HttpResponse::Ok().json(route_v),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+/// Add a route, 409 if there is one at its path.
+#[actix_web::post("/admin/routes")]
+async fn admin_create_route(
+    req: HttpRequest,
+    global_mutex: web::Data<Arc<Mutex<MemDataManager>>>,
+    verifier: web::Data<sign::Verifier>,
+    store: Option<web::Data<Arc<store::Store>>>,
+    body: web::Bytes,
+) -> impl Responder {
+    admin_put_route(&req, &global_mutex, &verifier, store, &body, false).await
+}
+
+/// Replace the route at its path, 404 if there is none.
+#[actix_web::put("/admin/routes")]
+async fn admin_update_route(
+    req: HttpRequest,
+    global_mutex: web::Data<Arc<Mutex<MemDataManager>>>,
+    verifier: web::Data<sign::Verifier>,
+    store: Option<web::Data<Arc<store::Store>>>,
+    body: web::Bytes,
+) -> impl Responder {
+    admin_put_route(&req, &global_mutex, &verifier, store, &body, true).await
+}
+
+#[actix_web::delete("/admin/routes")]
+async fn admin_delete_route(
+    req: HttpRequest,
+    global_mutex: web::Data<Arc<Mutex<MemDataManager>>>,
+    verifier: web::Data<sign::Verifier>,
+    store: Option<web::Data<Arc<store::Store>>>,
+    query: web::Query<PathQuery>,
+) -> impl Responder {
+    let mut global = global_mutex.lock().await;
+    if let Err(res) = authorize(&req, &mut *global, &verifier, b"", &["proxy"]).await {
+        return res;
+    }
+    match route::delete_route(&mut *global, &query.path).await {
+        Ok(true) => {
+            persist(store, &mut *global).await;
+            HttpResponse::Ok().finish()
+        }
+        Ok(false) => HttpResponse::NotFound().finish(),
+        Err(e) => HttpResponse::BadRequest().body(e.to_string()),
+    }
+}
+
+#[actix_web::get("/admin/mounts")]
+async fn admin_list_mounts(
+    req: HttpRequest,
+    global_mutex: web::Data<Arc<Mutex<MemDataManager>>>,
+    verifier: web::Data<sign::Verifier>,
+) -> impl Responder {
+    let mut global = global_mutex.lock().await;
+    if let Err(res) = authorize(&req, &mut *global, &verifier, b"", &[]).await {
+        return res;
+    }
+    match route::list_mounts(&mut *global).await {
+        Ok(mount_v) => HttpResponse::Ok().json(mount_v),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+/// Add a mount, 409 if there is one at its path.
+#[actix_web::post("/admin/mounts")]
+async fn admin_create_mount(
+    req: HttpRequest,
+    global_mutex: web::Data<Arc<Mutex<MemDataManager>>>,
+    verifier: web::Data<sign::Verifier>,
+    store: Option<web::Data<Arc<store::Store>>>,
+    body: web::Bytes,
+) -> impl Responder {
+    admin_put_mount(&req, &global_mutex, &verifier, store, &body, false).await
+}
+
+/// Replace the mount at its path, 404 if there is none.
+#[actix_web::put("/admin/mounts")]
+async fn admin_update_mount(
+    req: HttpRequest,
+    global_mutex: web::Data<Arc<Mutex<MemDataManager>>>,
+    verifier: web::Data<sign::Verifier>,
+    store: Option<web::Data<Arc<store::Store>>>,
+    body: web::Bytes,
+) -> impl Responder {
+    admin_put_mount(&req, &global_mutex, &verifier, store, &body, true).await
+}
+
+#[actix_web::delete("/admin/mounts")]
+async fn admin_delete_mount(
+    req: HttpRequest,
+    global_mutex: web::Data<Arc<Mutex<MemDataManager>>>,
+    verifier: web::Data<sign::Verifier>,
+    store: Option<web::Data<Arc<store::Store>>>,
+    query: web::Query<PathQuery>,
+) -> impl Responder {
+    let mut global = global_mutex.lock().await;
+    if let Err(res) = authorize(&req, &mut *global, &verifier, b"", &["mount"]).await {
+        return res;
+    }
+    match route::delete_mount(&mut *global, &query.path).await {
+        Ok(true) => {
+            persist(store, &mut *global).await;
+            HttpResponse::Ok().finish()
+        }
+        Ok(false) => HttpResponse::NotFound().finish(),
+        Err(e) => HttpResponse::BadRequest().body(e.to_string()),
+    }
+}
+
+#[actix_web::get("/admin/moon_servers")]
+async fn admin_list_moon_servers(
+    req: HttpRequest,
+    global_mutex: web::Data<Arc<Mutex<MemDataManager>>>,
+    verifier: web::Data<sign::Verifier>,
+) -> impl Responder {
+    let mut global = global_mutex.lock().await;
+    if let Err(res) = authorize(&req, &mut *global, &verifier, b"", &[]).await {
+        return res;
+    }
+    match route::list_moon_servers(&mut *global).await {
+        Ok(moon_server_v) => HttpResponse::Ok().json(moon_server_v),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+/// Add a moon server, 409 if it is already there.
+#[actix_web::post("/admin/moon_servers")]
+async fn admin_add_moon_server(
+    req: HttpRequest,
+    global_mutex: web::Data<Arc<Mutex<MemDataManager>>>,
+    verifier: web::Data<sign::Verifier>,
+    store: Option<web::Data<Arc<store::Store>>>,
+    body: web::Bytes,
+) -> impl Responder {
+    let mut global = global_mutex.lock().await;
+    if let Err(res) = authorize(&req, &mut *global, &verifier, &body, &["moon_server"]).await {
+        return res;
+    }
+    let request = match serde_json::from_slice::<MoonServerRequest>(&body) {
+        Ok(request) => request,
+        Err(e) => return HttpResponse::BadRequest().body(e.to_string()),
+    };
+    match route::add_moon_server(&mut *global, &request.uri).await {
+        Ok(true) => {
+            persist(store, &mut *global).await;
+            HttpResponse::Ok().finish()
+        }
+        Ok(false) => HttpResponse::Conflict().finish(),
+        Err(e) => HttpResponse::BadRequest().body(e.to_string()),
+    }
+}
+
+#[actix_web::delete("/admin/moon_servers")]
+async fn admin_delete_moon_server(
+    req: HttpRequest,
+    global_mutex: web::Data<Arc<Mutex<MemDataManager>>>,
+    verifier: web::Data<sign::Verifier>,
+    store: Option<web::Data<Arc<store::Store>>>,
+    query: web::Query<MoonServerRequest>,
+) -> impl Responder {
+    let mut global = global_mutex.lock().await;
+    if let Err(res) = authorize(&req, &mut *global, &verifier, b"", &["moon_server"]).await {
+        return res;
+    }
+    match route::delete_moon_server(&mut *global, &query.uri).await {
+        Ok(true) => {
+            persist(store, &mut *global).await;
+            HttpResponse::Ok().finish()
+        }
+        Ok(false) => HttpResponse::NotFound().finish(),
+        Err(e) => HttpResponse::BadRequest().body(e.to_string()),
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct AuditQuery {
+    /// Default: 100
+    limit: Option<usize>,
+    caller: Option<String>,
+    /// Seconds since the unix epoch.
+    since: Option<u64>,
+}
+
+/// Latest changes of the graph, newest first.
+#[actix_web::get("/admin/audit")]
+async fn admin_audit(
+    req: HttpRequest,
+    global_mutex: web::Data<Arc<Mutex<MemDataManager>>>,
+    verifier: web::Data<sign::Verifier>,
+    query: web::Query<AuditQuery>,
+) -> impl Responder {
+    match authorize(&req, &mut *global_mutex.lock().await, &verifier, b"", &[]).await {
+        Ok(caller) if caller.reads_audit() => (),
+        Ok(caller) => {
+            return HttpResponse::Forbidden()
+                .body(format!("{} may not read the audit log", caller.name()))
+        }
+        Err(res) => return res,
+    }
+    HttpResponse::Ok().json(audit::recent(
+        query.limit.unwrap_or(100),
+        query.caller.as_deref(),
+        query.since,
+    ))
+}
+
+#[derive(serde::Deserialize)]
+struct SubscribeQuery {
+    /// Paths starting at root, separated by `,`.
+    paths: String,
+}
+
+/// Values of paths of the graph, then their values on each change, as server-sent events.
+#[actix_web::get("/admin/subscribe")]
+async fn admin_subscribe(
+    req: HttpRequest,
+    global_mutex: web::Data<Arc<Mutex<MemDataManager>>>,
+    verifier: web::Data<sign::Verifier>,
+    query: web::Query<SubscribeQuery>,
+) -> impl Responder {
+    if let Err(res) = authorize(&req, &mut *global_mutex.lock().await, &verifier, b"", &[]).await {
+        return res;
+    }
+    let path_v = query
+        .paths
+        .split(',')
+        .map(|s| s.to_string())
+        .collect::<Vec<String>>();
+    let subscription = match subscription::Subscription::new(global_mutex.get_ref().clone(), path_v)
+    {
+        Ok(subscription) => subscription,
+        Err(e) => return HttpResponse::BadRequest().body(e.to_string()),
+    };
+    let stream = futures_util::stream::unfold(subscription, |mut subscription| async move {
+        match subscription.next().await {
+            Ok(event) => Some((
+                Ok::<_, actix_web::Error>(web::Bytes::from(event.to_sse())),
+                subscription,
+            )),
+            Err(e) => {
+                log::warn!("{e}\nwhen admin_subscribe");
+                None
+            }
+        }
+    });
     HttpResponse::Ok()
-        .content_type("application/json")
-        .body(serde_json::to_string(&rs).unwrap())
+        .content_type("text/event-stream")
+        .insert_header(("Cache-Control", "no-cache"))
+        .streaming(stream)
+}
+
+#[derive(serde::Deserialize)]
+struct ExportQuery {
+    /// Codes of root separated by `,`. Default: proxy,mount,moon_server,web_server
+    codes: Option<String>,
 }
 
-pub fn config(path: &str, src: &str) -> impl HttpServiceFactory {
+/// Export attributes of root as JSON.
+#[actix_web::get("/admin/export")]
+async fn admin_export(
+    req: HttpRequest,
+    global_mutex: web::Data<Arc<Mutex<MemDataManager>>>,
+    verifier: web::Data<sign::Verifier>,
+    query: web::Query<ExportQuery>,
+) -> impl Responder {
+    let mut global = global_mutex.lock().await;
+    if let Err(res) = authorize(&req, &mut *global, &verifier, b"", &[]).await {
+        return res;
+    }
+    let code_v = query
+        .codes
+        .as_deref()
+        .map(|codes| codes.split(',').map(|s| s.to_string()).collect())
+        .unwrap_or_default();
+    match export::export(&mut *global, &code_v).await {
+        Ok(export) => HttpResponse::Ok().json(export),
+        Err(e) => HttpResponse::BadRequest().body(e.to_string()),
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct ImportQuery {
+    /// Replace the sections in the body instead of merging them. Default: false
+    replace: Option<bool>,
+}
+
+/// Import an export in one transaction.
+#[actix_web::post("/admin/import")]
+async fn admin_import(
+    req: HttpRequest,
+    global_mutex: web::Data<Arc<Mutex<MemDataManager>>>,
+    verifier: web::Data<sign::Verifier>,
+    store: Option<web::Data<Arc<store::Store>>>,
+    query: web::Query<ImportQuery>,
+    body: web::Bytes,
+) -> impl Responder {
+    let export = match serde_json::from_slice::<export::Export>(&body) {
+        Ok(export) => export,
+        Err(e) => return HttpResponse::BadRequest().body(e.to_string()),
+    };
+    let mut global = global_mutex.lock().await;
+    let code_v = export.codes();
+    let code_v = code_v
+        .iter()
+        .map(|code| code.as_str())
+        .collect::<Vec<&str>>();
+    if let Err(res) = authorize(&req, &mut *global, &verifier, &body, &code_v).await {
+        return res;
+    }
+    let replace = query.replace.unwrap_or(false);
+    if let Err(e) = export::import(&mut *global, &export, replace).await {
+        return HttpResponse::BadRequest().body(e.to_string());
+    }
+    if let Some(store) = &store {
+        // Logged for the attributes a snapshot doesn't dump.
+        if let Err(e) = store.append(&export::import_script(&export, replace)) {
+            log::error!("{e}\nwhen admin_import");
+        }
+    }
+    persist(store, &mut *global).await;
+    HttpResponse::Ok().finish()
+}
+
+/// Add the route in the body if `replace` is false, else replace the one at its path.
+async fn admin_put_route(
+    req: &HttpRequest,
+    global_mutex: &Mutex<MemDataManager>,
+    verifier: &sign::Verifier,
+    store: Option<web::Data<Arc<store::Store>>>,
+    body: &[u8],
+    replace: bool,
+) -> HttpResponse {
+    let mut global = global_mutex.lock().await;
+    if let Err(res) = authorize(req, &mut *global, verifier, body, &["proxy"]).await {
+        return res;
+    }
+    let route = match serde_json::from_slice::<route::Route>(body) {
+        Ok(route) => route,
+        Err(e) => return HttpResponse::BadRequest().body(e.to_string()),
+    };
+    let exists = match route::list_routes(&mut *global).await {
+        Ok(route_v) => route_v.iter().any(|known| known.path == route.path),
+        Err(e) => return HttpResponse::InternalServerError().body(e.to_string()),
+    };
+    match (exists, replace) {
+        (true, false) => return HttpResponse::Conflict().finish(),
+        (false, true) => return HttpResponse::NotFound().finish(),
+        _ => (),
+    }
+    match route::put_route(&mut *global, &route).await {
+        Ok(()) => {
+            persist(store, &mut *global).await;
+            HttpResponse::Ok().json(route)
+        }
+        Err(e) => HttpResponse::BadRequest().body(e.to_string()),
+    }
+}
+
+/// Add the mount in the body if `replace` is false, else replace the one at its path.
+async fn admin_put_mount(
+    req: &HttpRequest,
+    global_mutex: &Mutex<MemDataManager>,
+    verifier: &sign::Verifier,
+    store: Option<web::Data<Arc<store::Store>>>,
+    body: &[u8],
+    replace: bool,
+) -> HttpResponse {
+    let mut global = global_mutex.lock().await;
+    if let Err(res) = authorize(req, &mut *global, verifier, body, &["mount"]).await {
+        return res;
+    }
+    let mount = match serde_json::from_slice::<route::Mount>(body) {
+        Ok(mount) => mount,
+        Err(e) => return HttpResponse::BadRequest().body(e.to_string()),
+    };
+    let exists = match route::list_mounts(&mut *global).await {
+        Ok(mount_v) => mount_v.iter().any(|known| known.path == mount.path),
+        Err(e) => return HttpResponse::InternalServerError().body(e.to_string()),
+    };
+    match (exists, replace) {
+        (true, false) => return HttpResponse::Conflict().finish(),
+        (false, true) => return HttpResponse::NotFound().finish(),
+        _ => (),
+    }
+    match route::put_mount(&mut *global, &mount).await {
+        Ok(()) => {
+            persist(store, &mut *global).await;
+            HttpResponse::Ok().json(mount)
+        }
+        Err(e) => HttpResponse::BadRequest().body(e.to_string()),
+    }
+}
+
+/// Snapshot the graph after a change through the admin API, if it is persisted.
+async fn persist(store: Option<web::Data<Arc<store::Store>>>, global: &mut MemDataManager) {
+    if let Some(store) = store {
+        if let Err(e) = store.snapshot(global).await {
+            log::error!("{e}\nwhen persist");
+        }
+    }
+}
+
+/// Let admins use the admin API, and anyone if `root->execute_open` is true, returning who
+/// called. Changes to `root->{code}` need a scope to write it, for each code of `code_v`.
+async fn authorize(
+    req: &HttpRequest,
+    global: &mut MemDataManager,
+    verifier: &sign::Verifier,
+    body: &[u8],
+    code_v: &[&str],
+) -> Result<Caller, HttpResponse> {
+    match authenticate(req, global, verifier, body).await? {
+        Caller::Anonymous if auth::is_open(global).await => Ok(Caller::Anonymous),
+        Caller::Anonymous => Err(HttpResponse::Unauthorized().body("signature required")),
+        Caller::Admin(id, scope_v) => {
+            match code_v
+                .iter()
+                .find(|code| !auth::allow_write(&scope_v, &format!("root->{code}")))
+            {
+                Some(code) => {
+                    Err(HttpResponse::Forbidden().body(format!("{id} may not change root->{code}")))
+                }
+                None => Ok(Caller::Admin(id, scope_v)),
+            }
+        }
+        Caller::Service(name) => {
+            Err(HttpResponse::Forbidden().body(format!("{name} may not use the admin API")))
+        }
+    }
+}
+
+/// Verify the call against the keys of admins and services, see [`sign::init`].
+///
+/// Unsigned calls pass as anonymous while no registry key is set. Admins get the scopes of their
+/// key in `root->admin_scope`, `all` if not set, narrowed to the `scope` claim of the call.
+async fn authenticate(
+    req: &HttpRequest,
+    global: &mut MemDataManager,
+    verifier: &sign::Verifier,
+    body: &[u8],
+) -> Result<Caller, HttpResponse> {
+    let mut keys = sign::get_keys();
+    let authorization = req
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|v| v.to_str().ok());
+    if authorization.is_none() && keys.is_empty() {
+        return Ok(Caller::Anonymous);
+    }
+    let admin_keys = sign::get_admin_keys();
+    if keys.is_empty() && admin_keys.is_empty() {
+        // Nothing to verify against, like moon servers that don't check signatures.
+        return Ok(Caller::Anonymous);
+    }
+    keys.extend(admin_keys.clone());
+    let caller = verify(req, global, verifier, &keys, &admin_keys, body).await?;
+    audit::identify(caller.name());
+    Ok(caller)
+}
+
+/// Verify a signed call, admins get their scopes.
+///
+/// Calls must be signed for this request: its method, its path and query and a host in
+/// `root->audience`, or the `Host` it came with if none is set.
+async fn verify(
+    req: &HttpRequest,
+    global: &mut MemDataManager,
+    verifier: &sign::Verifier,
+    keys: &BTreeMap<String, String>,
+    admin_keys: &BTreeMap<String, String>,
+    body: &[u8],
+) -> Result<Caller, HttpResponse> {
+    let authorization = req
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|v| v.to_str().ok());
+    let htu = req
+        .uri()
+        .path_and_query()
+        .map(|path_and_query| path_and_query.as_str())
+        .unwrap_or(req.path());
+    let mut aud_v = global
+        .get(&Path::from_str("root->audience"))
+        .await
+        .unwrap_or_default();
+    if aud_v.is_empty() {
+        aud_v.extend(
+            req.headers()
+                .get(HOST)
+                .and_then(|v| v.to_str().ok())
+                .map(|host| host.to_string()),
+        );
+    }
+    let call = (req.method().as_str(), htu);
+    let claims = match verifier.verify(keys, authorization, call, &aud_v, body) {
+        Ok(claims) => claims,
+        Err(e) => {
+            log::warn!("{e}\nwhen authenticate");
+            return Err(HttpResponse::Unauthorized().body(e.to_string()));
+        }
+    };
+    if !admin_keys.contains_key(&claims.sub) {
+        return Ok(Caller::Service(claims.sub));
+    }
+    let key_scope_v = match sign::get_admin_scope(global, &claims.sub).await {
+        Some(scope) => Scope::parse_list(&scope),
+        None => vec![Scope::All],
+    };
+    let scope_v = match &claims.scope {
+        Some(scope) => auth::narrow(&key_scope_v, &Scope::parse_list(scope)).ok_or_else(|| {
+            HttpResponse::Forbidden().body(format!("{} may not ask for {scope}", claims.sub))
+        })?,
+        None => key_scope_v,
+    };
+    Ok(Caller::Admin(claims.sub, scope_v))
+}
+
+/// Authenticate the call, then parse its body.
+async fn authenticate_json<T: serde::de::DeserializeOwned>(
+    req: &HttpRequest,
+    global: &mut MemDataManager,
+    verifier: &sign::Verifier,
+    body: &[u8],
+) -> Result<(T, Caller), HttpResponse> {
+    let caller = authenticate(req, global, verifier, body).await?;
+    let t =
+        serde_json::from_slice(body).map_err(|e| HttpResponse::BadRequest().body(e.to_string()))?;
+    Ok((t, caller))
+}
+
+/// A service may only change the web servers named as its key, an admin only with a scope on them.
+fn check_name(caller: &Caller, name: &str) -> Result<(), HttpResponse> {
+    match caller {
+        Caller::Service(sub) if sub != name => {
+            Err(HttpResponse::Forbidden().body(format!("{sub} may not change {name}")))
+        }
+        Caller::Admin(id, scope_v) if !auth::allow_write(scope_v, "root->web_server") => {
+            Err(HttpResponse::Forbidden().body(format!("{id} may not change {name}")))
+        }
+        _ => Ok(()),
+    }
+}
+
+pub fn config(path: &str, src: &str, registry: bool, gossip: bool) -> impl HttpServiceFactory {
     let src = src.to_string();
-    actix_web::web::scope(&path).service(execute).service(
+    let mut scope = actix_web::web::scope(&path)
+        .service(execute)
+        .service(admin_list_routes)
+        .service(admin_create_route)
+        .service(admin_update_route)
+        .service(admin_delete_route)
+        .service(admin_list_mounts)
+        .service(admin_create_mount)
+        .service(admin_update_mount)
+        .service(admin_delete_mount)
+        .service(admin_list_moon_servers)
+        .service(admin_add_moon_server)
+        .service(admin_delete_moon_server)
+        .service(admin_audit)
+        .service(admin_subscribe)
+        .service(admin_export)
+        .service(admin_import);
+    if registry {
+        scope = scope
+            .service(registry_register)
+            .service(registry_renew)
+            .service(registry_deregister)
+            .service(registry_lookup)
+            .service(registry_list)
+            .service(registry_watch);
+    }
+    if gossip {
+        scope = scope.service(self::gossip);
+    }
+    scope.service(
         Files::new("", &src)
             .index_file("index.html")
             .default_handler(actix_web::dev::fn_service(move |req: ServiceRequest| {
@@ -43,3 +1024,98 @@ pub fn config(path: &str, src: &str) -> impl HttpServiceFactory {
             })),
     )
 }
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{http::StatusCode, test::TestRequest};
+    use edge_lib::util::data::MemDataManager;
+    use tokio::sync::Mutex;
+
+    use super::{admin_put_mount, check_lines, ExecuteError};
+    use crate::{
+        err,
+        util::{route, sign, transaction},
+    };
+
+    #[test]
+    fn test_check_lines() {
+        let script = ["root->name = light _", "root->name = light"].map(|s| s.to_string());
+        let e = check_lines(&script).unwrap_err();
+        assert_eq!(e.line, Some(1));
+        let e = check_lines(&["name = light _".to_string()]).unwrap_err();
+        assert_eq!(e.line, Some(0));
+        assert!(check_lines(&script[..1]).is_ok());
+    }
+
+    #[test]
+    fn test_execute_error() {
+        let e = ExecuteError::script(err::Error::Line(1, "unknown function: foo".to_string()));
+        assert_eq!(
+            serde_json::to_value(&e).unwrap(),
+            serde_json::json!({"error": "unknown function: foo", "line": 1})
+        );
+        // Errors at no line have none.
+        let e = ExecuteError::script(err::Error::Other("engine failed".to_string()));
+        assert_eq!(e.line, None);
+        assert_eq!(
+            serde_json::to_value(&e).unwrap(),
+            serde_json::json!({"error": "engine failed"})
+        );
+
+        let e = ExecuteError::internal("broken");
+        assert_eq!(e.error, "internal error");
+        assert!(e.id.is_some());
+        assert_eq!(e.line, None);
+    }
+
+    #[test]
+    fn test_admin_put_mount() {
+        let root = std::env::temp_dir().join(format!("light-test-admin-{}", std::process::id()));
+        let www = root.join("www");
+        std::fs::create_dir_all(&www).unwrap();
+
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+            .block_on(async {
+                let mut global = MemDataManager::new(None);
+                transaction::execute(
+                    &mut global,
+                    &[
+                        "root->execute_open = true _".to_string(),
+                        format!("root->mount_root = {} _", root.display()),
+                    ],
+                )
+                .await
+                .unwrap();
+                let global = Mutex::new(global);
+                let verifier = sign::Verifier::new();
+                let put = |dir: String, replace: bool| {
+                    let global = &global;
+                    let verifier = &verifier;
+                    async move {
+                        let body = serde_json::json!({"path": "/static", "dir": dir}).to_string();
+                        let req = TestRequest::default().to_http_request();
+                        admin_put_mount(&req, global, verifier, None, body.as_bytes(), replace)
+                            .await
+                            .status()
+                    }
+                };
+
+                assert_eq!(
+                    put("/etc".to_string(), false).await,
+                    StatusCode::BAD_REQUEST
+                );
+                assert_eq!(put("/".to_string(), false).await, StatusCode::BAD_REQUEST);
+                let www_dir = www.to_string_lossy().to_string();
+                assert_eq!(put(www_dir.clone(), true).await, StatusCode::NOT_FOUND);
+                assert_eq!(put(www_dir.clone(), false).await, StatusCode::OK);
+                assert_eq!(put(www_dir.clone(), false).await, StatusCode::CONFLICT);
+                assert_eq!(put(www_dir, true).await, StatusCode::OK);
+                let mount_v = route::list_mounts(&mut *global.lock().await).await.unwrap();
+                assert_eq!(mount_v.len(), 1);
+            });
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,339 @@
+//! Sign and verify calls between lights and moon servers.
+//!
+//! A call carries `Authorization: Bearer {jwt}`, a JWT signed by HMAC-SHA256 with the secret of
+//! a key. Its claims hold the key id as `sub`, the time as `iat`, a nonce as `jti`, the SHA-256
+//! of the body as `sha`, the method as `htm`, the path and query as `htu` and the host it is sent
+//! to as `aud`, so that a call can't be altered, replayed or sent elsewhere. An optional `scope`
+//! narrows what the call may do.
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex, OnceLock,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use edge_lib::util::{
+    data::{AsDataManager, MemDataManager},
+    Path,
+};
+use hmac::{Hmac, Mac};
+use jwt::{SignWithKey, VerifyWithKey};
+use sha2::{Digest, Sha256};
+
+use crate::{err, util::registry::now};
+
+/// Seconds a call is valid around its `iat`.
+const MAX_SKEW: u64 = 300;
+
+// Public
+#[derive(Clone, Debug)]
+pub struct Credential {
+    pub id: String,
+    pub secret: String,
+}
+
+impl Credential {
+    /// Parse `{id}:{secret}`.
+    pub fn parse(s: &str) -> Option<Self> {
+        let (id, secret) = s.split_once(':')?;
+        if id.is_empty() || secret.is_empty() {
+            return None;
+        }
+        Some(Self {
+            id: id.to_string(),
+            secret: secret.to_string(),
+        })
+    }
+
+    /// Get the `Authorization` header for a call to `url` by `method` with the body.
+    pub fn authorization(&self, method: &str, url: &str, body: &[u8]) -> err::Result<String> {
+        let (htu, aud) = split_url(url)?;
+        self.sign(method, &htu, &aud, body)
+    }
+
+    /// Get the `Authorization` header for a call by `method` to `htu`, a path and query, at the
+    /// host `aud` with the body.
+    pub fn sign(&self, method: &str, htu: &str, aud: &str, body: &[u8]) -> err::Result<String> {
+        let claims = Claims {
+            sub: self.id.clone(),
+            iat: now(),
+            jti: nonce(),
+            sha: sha256_hex(body),
+            htm: method.to_uppercase(),
+            htu: htu.to_string(),
+            aud: aud.to_string(),
+            scope: None,
+        };
+        let token = claims
+            .sign_with_key(&hmac_key(&self.secret)?)
+            .map_err(|e| err::Error::Other(format!("{e}\nwhen sign")))?;
+        Ok(format!("Bearer {token}"))
+    }
+}
+
+/// Path with the query and host of `url`, the host as in the `Host` header, with the port unless
+/// it is the default.
+pub fn split_url(url: &str) -> err::Result<(String, String)> {
+    let url =
+        reqwest::Url::parse(url).map_err(|e| err::Error::Other(format!("{e}\nwhen split_url")))?;
+    let host = url.host_str().ok_or(err::Error::Other(format!(
+        "no host in {url}\nwhen split_url"
+    )))?;
+    let htu = match url.query() {
+        Some(query) => format!("{}?{query}", url.path()),
+        None => url.path().to_string(),
+    };
+    let aud = match url.port() {
+        Some(port) => format!("{host}:{port}"),
+        None => host.to_string(),
+    };
+    Ok((htu, aud))
+}
+
+/// Keys of this light, kept out of the graph so that no script can read or change them.
+#[derive(Clone, Debug, Default)]
+pub struct Keys {
+    /// Signs the calls of this light to moon servers.
+    pub credential: Option<Credential>,
+    /// Secrets of services by id.
+    pub key_mp: BTreeMap<String, String>,
+    /// Secrets of admins by id.
+    pub admin_key_mp: BTreeMap<String, String>,
+}
+
+impl Keys {
+    /// Parse `moon_key` and the keys of services and admins, each as `{id}:{secret}`.
+    pub fn parse(moon_key: &str, key_v: &[String], admin_key_v: &[String]) -> Self {
+        let parse_mp = |key_v: &[String]| {
+            key_v
+                .iter()
+                .filter_map(|s| {
+                    let credential = Credential::parse(s);
+                    if credential.is_none() {
+                        log::warn!("invalid key, expected {{id}}:{{secret}}");
+                    }
+                    credential
+                })
+                .map(|credential| (credential.id, credential.secret))
+                .collect()
+        };
+        Self {
+            credential: Credential::parse(moon_key),
+            key_mp: parse_mp(key_v),
+            admin_key_mp: parse_mp(admin_key_v),
+        }
+    }
+}
+
+/// Set the keys of this light.
+///
+/// Called once on start, lights without keys sign and verify nothing.
+pub fn init(keys: Keys) {
+    if KEYS.set(keys).is_err() {
+        log::warn!("keys are initialized already\nwhen init");
+    }
+}
+
+/// Credential this light signs its calls to moon servers with.
+pub fn get_credential() -> Option<Credential> {
+    get().credential.clone()
+}
+
+/// Keys of services.
+pub fn get_keys() -> BTreeMap<String, String> {
+    get().key_mp.clone()
+}
+
+/// Keys of admins.
+pub fn get_admin_keys() -> BTreeMap<String, String> {
+    get().admin_key_mp.clone()
+}
+
+/// Scopes granted to the admin `id`, from `root->admin_scope` as `{id}={scope},{scope}`.
+pub async fn get_admin_scope(global: &mut MemDataManager, id: &str) -> Option<String> {
+    global
+        .get(&Path::from_str("root->admin_scope"))
+        .await
+        .unwrap_or_default()
+        .iter()
+        .filter_map(|s| s.split_once('='))
+        .find(|(key, _)| *key == id)
+        .map(|(_, scope)| scope.to_string())
+}
+
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+pub struct Claims {
+    pub sub: String,
+    pub iat: u64,
+    pub jti: String,
+    pub sha: String,
+    pub htm: String,
+    pub htu: String,
+    pub aud: String,
+    /// Scopes separated by `,`, see `server::auth::Scope`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scope: Option<String>,
+}
+
+/// Verifies calls and remembers their nonces to reject replays.
+#[derive(Default)]
+pub struct Verifier {
+    nonce_mp: Mutex<HashMap<String, u64>>,
+}
+
+impl Verifier {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Verify the `Authorization` header of a call by `method` to `htu`, its path and query,
+    /// with the body, get its claims. The call must be addressed to one of `aud_v`.
+    pub fn verify(
+        &self,
+        keys: &BTreeMap<String, String>,
+        authorization: Option<&str>,
+        (method, htu): (&str, &str),
+        aud_v: &[String],
+        body: &[u8],
+    ) -> err::Result<Claims> {
+        let token = authorization
+            .and_then(|s| s.strip_prefix("Bearer "))
+            .ok_or(err::Error::Other(format!("no token")))?;
+        let unverified: jwt::Token<jwt::Header, Claims, _> = jwt::Token::parse_unverified(token)
+            .map_err(|e| err::Error::Other(format!("{e}\nwhen verify")))?;
+        let secret = keys
+            .get(&unverified.claims().sub)
+            .ok_or(err::Error::Other(format!("unknown key")))?;
+        let claims: Claims = token
+            .verify_with_key(&hmac_key(secret)?)
+            .map_err(|e| err::Error::Other(format!("{e}\nwhen verify")))?;
+
+        let now = now();
+        if claims.iat + MAX_SKEW < now || now + MAX_SKEW < claims.iat {
+            return Err(err::Error::Other(format!("token expired")));
+        }
+        if claims.sha != sha256_hex(body) {
+            return Err(err::Error::Other(format!("body altered")));
+        }
+        if !claims.htm.eq_ignore_ascii_case(method) || claims.htu != htu {
+            return Err(err::Error::Other(format!("signed for another request")));
+        }
+        if !aud_v
+            .iter()
+            .any(|aud| aud.eq_ignore_ascii_case(&claims.aud))
+        {
+            return Err(err::Error::Other(format!("signed for another host")));
+        }
+        let mut nonce_mp = self.nonce_mp.lock().unwrap();
+        nonce_mp.retain(|_, iat| *iat + MAX_SKEW >= now);
+        if nonce_mp.insert(claims.jti.clone(), claims.iat).is_some() {
+            return Err(err::Error::Other(format!("token replayed")));
+        }
+        Ok(claims)
+    }
+}
+
+/// A random enough id, unique in this process.
+pub fn nonce() -> String {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or(0);
+    let count = COUNTER.fetch_add(1, Ordering::Relaxed);
+    sha256_hex(format!("{nanos}:{count}:{}", std::process::id()).as_bytes())[..32].to_string()
+}
+
+// Private
+static KEYS: OnceLock<Keys> = OnceLock::new();
+
+fn get() -> &'static Keys {
+    static NONE: OnceLock<Keys> = OnceLock::new();
+    KEYS.get()
+        .unwrap_or_else(|| NONE.get_or_init(Keys::default))
+}
+
+fn hmac_key(secret: &str) -> err::Result<Hmac<Sha256>> {
+    Hmac::new_from_slice(secret.as_bytes())
+        .map_err(|e| err::Error::Other(format!("{e}\nwhen hmac_key")))
+}
+
+fn sha256_hex(body: &[u8]) -> String {
+    Sha256::digest(body)
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::{Credential, Verifier};
+
+    #[test]
+    fn test_verify() {
+        let credential = Credential::parse("api:secret").unwrap();
+        let keys = BTreeMap::from([("api".to_string(), "secret".to_string())]);
+        let verifier = Verifier::new();
+        let url = "http://moon:8080/light/registry/lookup/web?x=1";
+        let call = ("GET", "/light/registry/lookup/web?x=1");
+        let aud_v = ["moon:8080".to_string()];
+
+        let authorization = credential.authorization("GET", url, b"body").unwrap();
+        assert!(verifier
+            .verify(&keys, Some(&authorization), call, &aud_v, b"altered")
+            .is_err());
+        let authorization = credential.authorization("GET", url, b"body").unwrap();
+        assert_eq!(
+            verifier
+                .verify(&keys, Some(&authorization), call, &aud_v, b"body")
+                .unwrap()
+                .sub,
+            "api"
+        );
+        // Replayed
+        assert!(verifier
+            .verify(&keys, Some(&authorization), call, &aud_v, b"body")
+            .is_err());
+
+        let other = Credential::parse("api:other").unwrap();
+        let authorization = other.authorization("GET", url, b"body").unwrap();
+        assert!(verifier
+            .verify(&keys, Some(&authorization), call, &aud_v, b"body")
+            .is_err());
+
+        // Sent by another method, to another path or query, or to another host.
+        for (call, aud) in [
+            (("DELETE", call.1), "moon:8080"),
+            (("GET", "/light/registry/lookup/web?x=2"), "moon:8080"),
+            (call, "other:8080"),
+        ] {
+            let authorization = credential.authorization("GET", url, b"body").unwrap();
+            assert!(verifier
+                .verify(
+                    &keys,
+                    Some(&authorization),
+                    call,
+                    &[aud.to_string()],
+                    b"body"
+                )
+                .is_err());
+        }
+        // The default port is left out as in the `Host` header.
+        let authorization = credential
+            .authorization("POST", "https://moon:443/light/execute", b"")
+            .unwrap();
+        assert!(verifier
+            .verify(
+                &keys,
+                Some(&authorization),
+                ("POST", "/light/execute"),
+                &["moon".to_string()],
+                b""
+            )
+            .is_ok());
+    }
+}
@@ -0,0 +1,462 @@
+//! Persistence of the global graph in a data directory, so that changes made at runtime survive
+//! restarts.
+//!
+//! Scripts writing the graph through `{path}/execute` are appended to `log-{generation}.jsonl`,
+//! those failing partway without a rollback too. A snapshot dumps the routes, mounts, moon
+//! servers and web servers as a script rebuilding them into `snapshot.json`, then starts the log
+//! of the next generation. Routes, mounts and moon servers of the config are left out of the
+//! dump, since the config is applied again on each start: removing them from it takes effect.
+//! Logged scripts that wrote other paths are carried into the snapshot and replayed before the
+//! dump; those writing nothing but attributes of `root` are compacted into one script for each
+//! attribute, and past [`MAX_CARRY`] the oldest are dropped. On startup the snapshot, then its
+//! log, are replayed before the config is applied on top. Only the owner may read the files.
+use std::{
+    collections::BTreeSet,
+    fs::{self, File, OpenOptions},
+    io::{self, BufRead, Write},
+    os::unix::fs::{DirBuilderExt, OpenOptionsExt},
+    path::PathBuf,
+    sync::Arc,
+    time::Duration,
+};
+
+use edge_lib::util::{
+    data::{AsDataManager, MemDataManager},
+    engine::{AsEdgeEngine, EdgeEngine},
+    Path,
+};
+use tokio::{sync::Mutex, time};
+
+use crate::util::{
+    export,
+    server::auth::{self, Scope},
+};
+
+// Public
+pub struct Store {
+    dir: PathBuf,
+    state: std::sync::Mutex<State>,
+}
+
+impl Store {
+    /// Open the store in `dir`, replaying what it holds into the graph.
+    pub async fn open(dir: &str, global: &mut MemDataManager) -> io::Result<Self> {
+        let dir = PathBuf::from(dir);
+        fs::DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(&dir)
+            .map_err(|e| io::Error::other(format!("{e}\nwhen open")))?;
+        let snapshot = match fs::read_to_string(dir.join(SNAPSHOT)) {
+            Ok(content) => serde_json::from_str::<Snapshot>(&content)
+                .map_err(|e| io::Error::other(format!("{e}\nwhen open")))?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Snapshot::default(),
+            Err(e) => return Err(io::Error::other(format!("{e}\nwhen open"))),
+        };
+        log::info!(
+            "replaying generation {} in {}",
+            snapshot.generation,
+            dir.display()
+        );
+        for script in snapshot.carry.iter().chain([&snapshot.script]) {
+            execute(global, script).await;
+        }
+
+        let mut carry_v = snapshot.carry;
+        let log_path = dir.join(log_name(snapshot.generation));
+        if let Ok(file) = File::open(&log_path) {
+            for line in io::BufReader::new(file).lines() {
+                let line = line.map_err(|e| io::Error::other(format!("{e}\nwhen open")))?;
+                // A crash may leave the last line torn, it was never acknowledged.
+                let script = match serde_json::from_str::<Vec<String>>(&line) {
+                    Ok(script) => script,
+                    Err(e) => {
+                        log::warn!("skipping the rest of {}: {e}", log_path.display());
+                        break;
+                    }
+                };
+                execute(global, &script).await;
+                if !is_dumped(&script) {
+                    carry_v.push(script);
+                }
+            }
+        }
+        let log = open_log(&log_path)?;
+        Ok(Self {
+            dir,
+            state: std::sync::Mutex::new(State {
+                generation: snapshot.generation,
+                log,
+                carry_v,
+                config: export::Export::default(),
+            }),
+        })
+    }
+
+    pub async fn run(self: Arc<Self>, global: Arc<Mutex<MemDataManager>>) -> io::Result<()> {
+        loop {
+            let interval = get_snapshot_interval(&mut *global.lock().await).await;
+            time::sleep(interval).await;
+
+            if let Err(e) = self.snapshot(&mut *global.lock().await).await {
+                log::error!("{e}\nwhen run");
+            }
+        }
+    }
+
+    /// Leave the routes, mounts and moon servers of the config out of snapshots.
+    pub fn set_config(&self, config: export::Export) {
+        self.state.lock().unwrap().config = config;
+    }
+
+    /// Log a script executed on the graph, scripts that write nothing are skipped. Scripts
+    /// failing partway are logged too, replaying them fails at the same line.
+    ///
+    /// Called with the graph locked, so that the log is in the order the scripts were executed.
+    pub fn append(&self, script: &[String]) -> io::Result<()> {
+        if auth::check_script(&[Scope::Read], script).is_ok() {
+            return Ok(());
+        }
+        let line = serde_json::to_string(script)
+            .map_err(|e| io::Error::other(format!("{e}\nwhen append")))?;
+        let mut state = self.state.lock().unwrap();
+        state
+            .log
+            .write_all(format!("{line}\n").as_bytes())
+            .and_then(|_| state.log.sync_data())
+            .map_err(|e| io::Error::other(format!("{e}\nwhen append")))?;
+        if !is_dumped(script) {
+            state.carry_v.push(script.to_vec());
+        }
+        Ok(())
+    }
+
+    /// Write a snapshot of the graph and start a new log.
+    ///
+    /// Called with the graph locked, so that no script is logged in between.
+    pub async fn snapshot(&self, global: &mut MemDataManager) -> io::Result<()> {
+        let (carry_v, config) = {
+            let state = self.state.lock().unwrap();
+            (state.carry_v.clone(), state.config.clone())
+        };
+        let script = dump_script(global, &config).await?;
+        let mut carry_v = compact(global, &carry_v).await?;
+        if carry_v.len() > MAX_CARRY {
+            let dropped = carry_v.len() - MAX_CARRY;
+            log::warn!("dropping the {dropped} oldest carried scripts\nwhen snapshot");
+            carry_v.drain(..dropped);
+        }
+        let mut state = self.state.lock().unwrap();
+        let snapshot = Snapshot {
+            generation: state.generation + 1,
+            carry: carry_v.clone(),
+            script,
+        };
+        let content = serde_json::to_string(&snapshot)
+            .map_err(|e| io::Error::other(format!("{e}\nwhen snapshot")))?;
+        let tmp_path = self.dir.join(format!("{SNAPSHOT}.tmp"));
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(&tmp_path)
+            .map_err(|e| io::Error::other(format!("{e}\nwhen snapshot")))?;
+        file.write_all(content.as_bytes())
+            .and_then(|_| file.sync_all())
+            .and_then(|_| fs::rename(&tmp_path, self.dir.join(SNAPSHOT)))
+            .map_err(|e| io::Error::other(format!("{e}\nwhen snapshot")))?;
+
+        // The snapshot holds all that was logged, the old log is obsolete from here.
+        state.log = open_log(&self.dir.join(log_name(snapshot.generation)))?;
+        if let Err(e) = fs::remove_file(self.dir.join(log_name(state.generation))) {
+            log::warn!("{e}\nwhen snapshot");
+        }
+        state.generation = snapshot.generation;
+        state.carry_v = carry_v;
+        log::debug!("snapshot generation {}", state.generation);
+        Ok(())
+    }
+}
+
+// Private
+const SNAPSHOT: &str = "snapshot.json";
+
+const DEFAULT_SNAPSHOT_INTERVAL: u64 = 60;
+
+/// Scripts carried into a snapshot at most.
+const MAX_CARRY: usize = 1000;
+
+struct State {
+    generation: u64,
+    log: File,
+    /// Logged scripts writing paths the snapshot doesn't dump.
+    carry_v: Vec<Vec<String>>,
+    /// Entries of the config, left out of snapshots.
+    config: export::Export,
+}
+
+#[derive(Default, serde::Deserialize, serde::Serialize)]
+struct Snapshot {
+    generation: u64,
+    carry: Vec<Vec<String>>,
+    script: Vec<String>,
+}
+
+fn log_name(generation: u64) -> String {
+    format!("log-{generation}.jsonl")
+}
+
+fn open_log(path: &std::path::Path) -> io::Result<File> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .mode(0o600)
+        .open(path)
+        .map_err(|e| io::Error::other(format!("{e}\nwhen open_log {}", path.display())))
+}
+
+/// Whether the script only writes what the snapshot dumps.
+fn is_dumped(script: &[String]) -> bool {
+    let scope_v = export::SECTIONS.map(|code| Scope::Write(code.to_string()));
+    auth::check_script(&scope_v, script).is_ok()
+}
+
+/// Carried scripts, with those writing nothing but attributes of `root`, see [`plain_codes`],
+/// replaced by a script for each attribute setting the values it has now. Attributes written otherwise by any script
+/// are left to their scripts, and nothing is compacted while a script can't be traced.
+async fn compact(
+    global: &mut MemDataManager,
+    carry_v: &[Vec<String>],
+) -> io::Result<Vec<Vec<String>>> {
+    let mut plain_set = BTreeSet::new();
+    let mut mixed_set = BTreeSet::new();
+    for script in carry_v {
+        match plain_codes(script) {
+            Some(code_set) => plain_set.extend(code_set),
+            None => match auth::written_paths(script) {
+                Ok(path_v) => mixed_set.extend(
+                    path_v
+                        .iter()
+                        .filter_map(|(_, path)| path.split("->").nth(1).map(|s| s.to_string())),
+                ),
+                Err(_) => return Ok(carry_v.to_vec()),
+            },
+        }
+    }
+    let code_set = plain_set
+        .difference(&mixed_set)
+        .cloned()
+        .collect::<BTreeSet<String>>();
+    let mut compacted_v = carry_v
+        .iter()
+        .filter(|script| {
+            plain_codes(script)
+                .map(|script_code_set| !script_code_set.is_subset(&code_set))
+                .unwrap_or(true)
+        })
+        .cloned()
+        .collect::<Vec<Vec<String>>>();
+    for code in &code_set {
+        let value_v = global
+            .get(&Path::from_str(&format!("root->{code}")))
+            .await
+            .map_err(|e| io::Error::other(format!("{}\nwhen compact", e.message())))?;
+        let mut script = vec![format!("root->{code} = _ _")];
+        script.extend(
+            value_v
+                .iter()
+                .map(|value| format!("root->{code} append root->{code} {value}")),
+        );
+        compacted_v.push(script);
+    }
+    Ok(compacted_v)
+}
+
+/// Attributes of `root` the script sets, if it writes nothing but attributes of `root` and
+/// variables, and makes no node: what it did is then all in the values of those attributes.
+fn plain_codes(script: &[String]) -> Option<BTreeSet<String>> {
+    let mut code_set = BTreeSet::new();
+    for line in script {
+        let token_v = line.split_whitespace().collect::<Vec<&str>>();
+        let target = match token_v[..] {
+            [target, _, first, second] if first != "?" && second != "?" => target,
+            _ => return None,
+        };
+        match target.split_once("->") {
+            Some(("root", code)) if !code.contains("->") => {
+                code_set.insert(code.to_string());
+            }
+            Some(("$", var)) if !var.contains("->") => (),
+            _ => return None,
+        }
+    }
+    Some(code_set)
+}
+
+/// Script rebuilding the routes, mounts, moon servers and web servers as they are, but those of
+/// the config.
+async fn dump_script(
+    global: &mut MemDataManager,
+    config: &export::Export,
+) -> io::Result<Vec<String>> {
+    let mut export = export::export(global, &[])
+        .await
+        .map_err(|e| io::Error::other(format!("{e}\nwhen dump_script")))?;
+    if let (Some(route_v), Some(config_v)) = (&mut export.proxy, &config.proxy) {
+        route_v.retain(|route| !config_v.iter().any(|config| config.path == route.path));
+    }
+    if let (Some(mount_v), Some(config_v)) = (&mut export.mount, &config.mount) {
+        mount_v.retain(|mount| !config_v.iter().any(|config| config.path == mount.path));
+    }
+    if let (Some(moon_server_v), Some(config_v)) = (&mut export.moon_server, &config.moon_server) {
+        moon_server_v.retain(|moon_server| !config_v.contains(moon_server));
+    }
+    Ok(export::import_script(&export, true))
+}
+
+/// Replay a script, a script failing now failed when it was logged too.
+async fn execute(global: &mut MemDataManager, script: &[String]) {
+    if let Err(e) = EdgeEngine::new(global).execute_script(script).await {
+        log::warn!("{}\nwhen execute", e.message());
+    }
+}
+
+/// Seconds between snapshots, from `root->snapshot_interval`.
+async fn get_snapshot_interval(global: &mut MemDataManager) -> Duration {
+    let interval = global
+        .get(&Path::from_str("root->snapshot_interval"))
+        .await
+        .ok()
+        .and_then(|value_v| value_v.first().and_then(|s| s.parse::<u64>().ok()))
+        .unwrap_or(DEFAULT_SNAPSHOT_INTERVAL);
+    Duration::from_secs(interval.max(1))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, os::unix::fs::PermissionsExt};
+
+    use edge_lib::util::{
+        data::{AsDataManager, MemDataManager},
+        Path,
+    };
+
+    use super::{is_dumped, plain_codes, Store, SNAPSHOT};
+    use crate::util::{export, route, transaction};
+
+    #[test]
+    fn test_is_dumped() {
+        let script = [
+            "$->$:proxy = ? _",
+            "$->$:proxy->path = /api _",
+            "$->$:proxy->name = api _",
+            "root->proxy append root->proxy $->$:proxy",
+        ]
+        .map(|s| s.to_string());
+        assert!(is_dumped(&script));
+        assert!(!is_dumped(&["root->lease = 60 _".to_string()]));
+    }
+
+    #[test]
+    fn test_plain_codes() {
+        let script = [
+            "$->$:lease = root->lease _",
+            "root->heartbeat = $->$:lease _",
+            "root->meta append root->meta zone=a",
+        ]
+        .map(|s| s.to_string());
+        assert_eq!(
+            plain_codes(&script)
+                .unwrap()
+                .into_iter()
+                .collect::<Vec<String>>(),
+            vec!["heartbeat", "meta"]
+        );
+        // New nodes and what is written on nodes are not in the values of root.
+        let script = ["$->$:node = ? _", "root->node = $->$:node _"].map(|s| s.to_string());
+        assert!(plain_codes(&script).is_none());
+        let script =
+            ["$->$:proxy = root->proxy _", "$->$:proxy->name = api _"].map(|s| s.to_string());
+        assert!(plain_codes(&script).is_none());
+    }
+
+    #[test]
+    fn test_open() {
+        let dir = std::env::temp_dir().join(format!("light-test-store-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let dir_str = dir.to_string_lossy().to_string();
+
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+            .block_on(async {
+                let mut global = MemDataManager::new(None);
+                let store = Store::open(&dir_str, &mut global).await.unwrap();
+                let route = route::Route {
+                    path: "/api".to_string(),
+                    upstreams: vec!["http://127.0.0.1:8080".to_string()],
+                    ..Default::default()
+                };
+                for script in [
+                    route::route_script(&route),
+                    vec!["root->lease = 60 _".to_string()],
+                    vec!["root->lease = 90 _".to_string()],
+                ] {
+                    transaction::execute(&mut global, &script).await.unwrap();
+                    store.append(&script).unwrap();
+                }
+                let check = |dir_str: String| async move {
+                    let mut replayed = MemDataManager::new(None);
+                    Store::open(&dir_str, &mut replayed).await.unwrap();
+                    assert_eq!(
+                        route::list_routes(&mut replayed).await.unwrap(),
+                        vec![route::Route {
+                            path: "/api".to_string(),
+                            upstreams: vec!["http://127.0.0.1:8080".to_string()],
+                            ..Default::default()
+                        }]
+                    );
+                    assert_eq!(
+                        replayed.get(&Path::from_str("root->lease")).await.unwrap(),
+                        vec!["90".to_string()]
+                    );
+                };
+                // Replayed from the log.
+                check(dir_str.clone()).await;
+
+                // Then from a snapshot, carrying the lease once.
+                store.snapshot(&mut global).await.unwrap();
+                assert_eq!(
+                    store.state.lock().unwrap().carry_v,
+                    vec![vec![
+                        "root->lease = _ _".to_string(),
+                        "root->lease append root->lease 90".to_string(),
+                    ]]
+                );
+                check(dir_str.clone()).await;
+
+                let mode = fs::metadata(dir.join(SNAPSHOT))
+                    .unwrap()
+                    .permissions()
+                    .mode();
+                assert_eq!(mode & 0o777, 0o600);
+
+                // Routes of the config are left to the config.
+                store.set_config(export::Export {
+                    proxy: Some(vec![route.clone()]),
+                    ..Default::default()
+                });
+                store.snapshot(&mut global).await.unwrap();
+                let mut replayed = MemDataManager::new(None);
+                Store::open(&dir_str, &mut replayed).await.unwrap();
+                assert!(route::list_routes(&mut replayed).await.unwrap().is_empty());
+                assert_eq!(
+                    replayed.get(&Path::from_str("root->lease")).await.unwrap(),
+                    vec!["90".to_string()]
+                );
+            });
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,231 @@
+//! Subscriptions to paths of the global graph, served as server-sent events.
+//!
+//! Scripts that succeed are published by the attributes of `root` they write, found as
+//! [`auth::written_paths`] does. Scripts whose writes can't be traced are published as writing
+//! all of them. A subscriber gets the values of all its paths first, then the
+//! values of the paths each change touched, read once the change is done. A subscriber lagging
+//! behind the changes gets the values of all its paths again.
+use std::{
+    collections::BTreeSet,
+    sync::{Arc, OnceLock},
+    time::Duration,
+};
+
+use edge_lib::util::{
+    data::{AsDataManager, MemDataManager},
+    Path,
+};
+use tokio::{
+    sync::{broadcast, Mutex},
+    time,
+};
+
+use crate::{
+    err,
+    util::{registry, route, server::auth},
+};
+
+// Public
+pub enum Event {
+    /// Values of all the paths.
+    Snapshot(serde_json::Map<String, serde_json::Value>),
+    /// Values of the paths a change touched.
+    Change(serde_json::Map<String, serde_json::Value>),
+    /// Sent while nothing changes, so that dead connections are found.
+    KeepAlive,
+}
+
+impl Event {
+    /// The event in the `text/event-stream` format.
+    pub fn to_sse(&self) -> String {
+        match self {
+            Event::Snapshot(value_mp) => format!(
+                "event: snapshot\ndata: {}\n\n",
+                serde_json::Value::Object(value_mp.clone())
+            ),
+            Event::Change(value_mp) => format!(
+                "event: change\ndata: {}\n\n",
+                serde_json::Value::Object(value_mp.clone())
+            ),
+            Event::KeepAlive => ": keep-alive\n\n".to_string(),
+        }
+    }
+}
+
+pub struct Subscription {
+    global: Arc<Mutex<MemDataManager>>,
+    path_v: Vec<String>,
+    receiver: broadcast::Receiver<Arc<BTreeSet<String>>>,
+    started: bool,
+}
+
+impl Subscription {
+    /// Subscribe to `path_v`, like `root->proxy` or `root->web_server`.
+    pub fn new(global: Arc<Mutex<MemDataManager>>, path_v: Vec<String>) -> err::Result<Self> {
+        if path_v.is_empty() {
+            return Err(err::Error::Other(format!("no path to subscribe to")));
+        }
+        for path in &path_v {
+            check_path(path)?;
+        }
+        Ok(Self {
+            global,
+            path_v,
+            // Subscribed before the snapshot is read, so that no change is missed in between.
+            receiver: changes(),
+            started: false,
+        })
+    }
+
+    pub async fn next(&mut self) -> err::Result<Event> {
+        if !self.started {
+            self.started = true;
+            return Ok(Event::Snapshot(self.read(&self.path_v).await?));
+        }
+        loop {
+            let mut code_set = match time::timeout(KEEP_ALIVE, self.receiver.recv()).await {
+                Err(_) => return Ok(Event::KeepAlive),
+                Ok(Ok(code_set)) => (*code_set).clone(),
+                Ok(Err(broadcast::error::RecvError::Lagged(n))) => {
+                    log::debug!("missed {n} changes, sending a snapshot");
+                    return Ok(Event::Snapshot(self.read(&self.path_v).await?));
+                }
+                Ok(Err(broadcast::error::RecvError::Closed)) => {
+                    return Err(err::Error::Other(format!("closed\nwhen next")))
+                }
+            };
+            // Changes queued already are answered at once.
+            while let Ok(more) = self.receiver.try_recv() {
+                code_set.extend(more.iter().cloned());
+            }
+            let path_v = self
+                .path_v
+                .iter()
+                .filter(|path| root_code(path).map(|code| touches(&code_set, code)) == Some(true))
+                .cloned()
+                .collect::<Vec<String>>();
+            if !path_v.is_empty() {
+                return Ok(Event::Change(self.read(&path_v).await?));
+            }
+        }
+    }
+
+    async fn read(
+        &self,
+        path_v: &[String],
+    ) -> err::Result<serde_json::Map<String, serde_json::Value>> {
+        let mut global = self.global.lock().await;
+        let mut value_mp = serde_json::Map::new();
+        for path in path_v {
+            value_mp.insert(path.clone(), value(&mut *global, path).await?);
+        }
+        Ok(value_mp)
+    }
+}
+
+/// Publish the change made by a script, scripts that write nothing are skipped.
+pub fn publish(script: &[String]) {
+    let code_set = match auth::written_paths(script) {
+        Ok(path_v) => path_v
+            .iter()
+            .filter_map(|(_, path)| root_code(path).map(|code| code.to_string()))
+            .collect::<BTreeSet<String>>(),
+        Err(_) => BTreeSet::from([ANY.to_string()]),
+    };
+    if code_set.is_empty() {
+        return;
+    }
+    // Fails only while nobody subscribed.
+    let _ = sender().send(Arc::new(code_set));
+}
+
+/// Changes as they are published, each as the codes of `root` it writes, see [`touches`].
+pub fn changes() -> broadcast::Receiver<Arc<BTreeSet<String>>> {
+    sender().subscribe()
+}
+
+/// Whether a change may have written `root->{code}`.
+pub fn touches(code_set: &BTreeSet<String>, code: &str) -> bool {
+    code_set.contains(code) || code_set.contains(ANY)
+}
+
+// Private
+const CAPACITY: usize = 256;
+
+/// Published for changes that may have written anything.
+const ANY: &str = "*";
+
+const KEEP_ALIVE: Duration = Duration::from_secs(15);
+
+static SENDER: OnceLock<broadcast::Sender<Arc<BTreeSet<String>>>> = OnceLock::new();
+
+fn sender() -> &'static broadcast::Sender<Arc<BTreeSet<String>>> {
+    SENDER.get_or_init(|| broadcast::channel(CAPACITY).0)
+}
+
+/// The attribute of `root` a path starts with.
+fn root_code(path: &str) -> Option<&str> {
+    let mut segment_it = path.split("->");
+    if segment_it.next() != Some("root") {
+        return None;
+    }
+    segment_it.next()
+}
+
+fn check_path(path: &str) -> err::Result<()> {
+    let valid = root_code(path).is_some()
+        && !path.contains(char::is_whitespace)
+        && path
+            .split("->")
+            .all(|segment| !segment.is_empty() && !segment.starts_with('$'));
+    if !valid {
+        return Err(err::Error::Other(format!("invalid path: {path:?}")));
+    }
+    Ok(())
+}
+
+/// Routes, mounts, moon servers and web servers as the admin API lists them, other paths as
+/// their values in the graph.
+async fn value(global: &mut MemDataManager, path: &str) -> err::Result<serde_json::Value> {
+    match path {
+        "root->proxy" => to_value(route::list_routes(global).await?),
+        "root->mount" => to_value(route::list_mounts(global).await?),
+        "root->moon_server" => to_value(route::list_moon_servers(global).await?),
+        "root->web_server" => to_value(registry::lookup(global, None).await?),
+        _ => to_value(
+            global
+                .get(&Path::from_str(path))
+                .await
+                .map_err(|e| err::Error::Other(e.message().to_string()))?,
+        ),
+    }
+}
+
+fn to_value(value: impl serde::Serialize) -> err::Result<serde_json::Value> {
+    serde_json::to_value(value).map_err(|e| err::Error::Other(format!("{e}\nwhen to_value")))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use super::{check_path, touches};
+
+    #[test]
+    fn test_check_path() {
+        assert!(check_path("root->proxy").is_ok());
+        assert!(check_path("root->meta").is_ok());
+        assert!(check_path("root").is_err());
+        assert!(check_path("$->$:proxy").is_err());
+        assert!(check_path("root->$:proxy").is_err());
+        assert!(check_path("root->proxy ").is_err());
+    }
+
+    #[test]
+    fn test_touches() {
+        let code_set = BTreeSet::from(["proxy".to_string()]);
+        assert!(touches(&code_set, "proxy"));
+        assert!(!touches(&code_set, "mount"));
+        assert!(touches(&BTreeSet::from([super::ANY.to_string()]), "mount"));
+    }
+}
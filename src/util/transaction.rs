@@ -0,0 +1,608 @@
+//! All-or-nothing execution of edge scripts on the global graph.
+//!
+//! A script runs with each line writing an attribute preceded by lines recording the nodes it
+//! writes and the values they hold, and with the nodes it makes recorded as they are made, so
+//! that what it really wrote is known once it ran, whatever its variables held. If the script
+//! fails or panics, the values it overwrote are written back, so the graph is as it was before.
+//! New nodes the script made are left unreachable once the lists it added them to are restored.
+//! What scripts of scoped admins wrote is checked against their scopes before it is kept, see
+//! [`execute_scoped`]. A dry run writes the values back whatever happens, comparing them with
+//! the values the script left first, so it leaves nothing behind.
+use std::{collections::BTreeMap, panic::AssertUnwindSafe};
+
+use edge_lib::util::{
+    data::{AsDataManager, MemDataManager},
+    engine::{AsEdgeEngine, EdgeEngine},
+    Path,
+};
+use futures_util::FutureExt;
+
+use crate::{
+    err,
+    util::{
+        audit,
+        server::auth::{self, Scope},
+        sign, subscription,
+    },
+};
+
+// Public
+/// Execute the script, rolling back what it wrote if it fails, and audit and publish it if it
+/// succeeds.
+pub async fn execute(global: &mut MemDataManager, script: &[String]) -> err::Result<Vec<String>> {
+    execute_scoped(global, script, &[Scope::All]).await
+}
+
+/// Execute the script as [`execute`] does, rolling it back too if what it really wrote is out
+/// of the scopes.
+///
+/// Attributes of `root` must be allowed by a scope. Other nodes written must be new, or listed in
+/// `root->{code}` for a `write:{code}` scope and in no other attribute of [`auth::NODE_CODE_V`].
+/// Nodes the script adds anywhere must be new, `root` never, and listed in no such attribute out
+/// of the scopes, so that no node can be reached from where it doesn't belong.
+pub async fn execute_scoped(
+    global: &mut MemDataManager,
+    script: &[String],
+    scope_v: &[Scope],
+) -> err::Result<Vec<String>> {
+    let Run {
+        rs,
+        written_v,
+        new_v,
+    } = run(global, script).await;
+    let written_v = written_v
+        .map_err(|e| err::Error::Other(format!("{e}\nwhen execute_scoped, not rolled back")))?;
+    let rs = match rs {
+        Ok(Ok(rs)) => match new_v {
+            Ok(new_v) => check_written(global, &written_v, &new_v, scope_v)
+                .await
+                .map(|_| rs),
+            Err(e) => Err(e),
+        },
+        Ok(Err(e)) => Err(e),
+        Err(panic) => {
+            rollback(global, &written_v).await;
+            std::panic::resume_unwind(panic)
+        }
+    };
+    if rs.is_err() {
+        rollback(global, &written_v).await;
+    }
+    let rs = rs?;
+    audit::record(script);
+    subscription::publish(script);
+    Ok(rs)
+}
+
+/// Execute the script keeping what it wrote before it failed, and audit and publish it either way.
+pub async fn execute_partial(
+    global: &mut MemDataManager,
+    script: &[String],
+) -> err::Result<Vec<String>> {
+    let Run { rs, .. } = run(global, script).await;
+    audit::record(script);
+    subscription::publish(script);
+    match rs {
+        Ok(rs) => rs,
+        Err(panic) => std::panic::resume_unwind(panic),
+    }
+}
+
+/// An edge from `source` to `target` by `code`.
+#[derive(Clone, Debug, PartialEq, serde::Serialize)]
+pub struct Edge {
+    pub source: String,
+    pub code: String,
+    pub target: String,
+}
+
+/// What a script would do to the graph.
+#[derive(Clone, Debug, serde::Serialize)]
+pub struct DryRun {
+    pub result: Vec<String>,
+    pub added: Vec<Edge>,
+    pub removed: Vec<Edge>,
+}
+
+/// Execute the script and roll back what it wrote even if it succeeds, reporting the edges it
+/// added to or removed from the nodes reached from `root`. What it wrote on the nodes it made is
+/// cleared too.
+pub async fn dry_run(global: &mut MemDataManager, script: &[String]) -> err::Result<DryRun> {
+    let Run {
+        rs,
+        written_v,
+        new_v,
+    } = run(global, script).await;
+    let written_v =
+        written_v.map_err(|e| err::Error::Other(format!("{e}\nwhen dry_run, not rolled back")))?;
+    // Read before rolling back, but rolled back even if reading fails.
+    let after_rs = read(global, &written_v).await;
+    rollback(global, &written_v).await;
+    let result = match rs {
+        Ok(Ok(result)) => result,
+        Ok(Err(e)) => return Err(e),
+        Err(panic) => std::panic::resume_unwind(panic),
+    };
+    let new_v = new_v?;
+    let (mut added, mut removed) = diff(&written_v, &after_rs?);
+    // Nodes the script made show where they are linked from.
+    added.retain(|edge| !new_v.contains(&edge.source));
+    removed.retain(|edge| !new_v.contains(&edge.source));
+    Ok(DryRun {
+        result,
+        added,
+        removed,
+    })
+}
+
+// Private
+/// Values of the attribute `code` of `node` before a script.
+#[derive(Debug, PartialEq)]
+struct Undo {
+    node: String,
+    code: String,
+    value_v: Vec<String>,
+}
+
+/// A run of a script, with what it wrote.
+struct Run {
+    /// Output of the script, or its failure at the line it failed at, or its panic.
+    rs: std::thread::Result<err::Result<Vec<String>>>,
+    /// Attributes it wrote, with their values before.
+    written_v: err::Result<Vec<Undo>>,
+    /// Nodes it made.
+    new_v: err::Result<Vec<String>>,
+}
+
+/// Run the script, recorded by [`record`] and [`record_new`], then clear the records.
+async fn run(global: &mut MemDataManager, script: &[String]) -> Run {
+    let nonce = sign::nonce();
+    let collector = format!("root->new_{nonce}");
+    let recording = record_new(&record(script, &nonce), &collector, &nonce);
+    let rs = AssertUnwindSafe(EdgeEngine::new(global).execute_script(&recording))
+        .catch_unwind()
+        .await;
+    let rs = match rs {
+        Ok(Ok(rs)) => Ok(Ok(rs)),
+        Ok(Err(e)) => {
+            let e = err::Error::Other(e.message().to_string());
+            Ok(Err(at_line(global, &nonce, e).await))
+        }
+        Err(panic) => Err(panic),
+    };
+    let new_v = get(global, &collector).await;
+    let mut clear_script = vec![
+        format!("{collector} = _ _"),
+        format!("root->line_{nonce} = _ _"),
+    ];
+    let written_v = match read_written(global, script, &nonce).await {
+        Ok((written_v, clear_v)) => {
+            clear_script.extend(clear_v);
+            Ok(written_v)
+        }
+        Err(e) => Err(e),
+    };
+    restore(global, &clear_script).await;
+    Run {
+        rs,
+        written_v,
+        new_v,
+    }
+}
+
+async fn rollback(global: &mut MemDataManager, undo_v: &[Undo]) {
+    restore(global, &rollback_script(undo_v)).await;
+}
+
+async fn restore(global: &mut MemDataManager, script: &[String]) {
+    if let Err(e) = EdgeEngine::new(global).execute_script(script).await {
+        log::error!("{}\nwhen rollback", e.message());
+    }
+}
+
+/// Script writing the saved values back.
+fn rollback_script(undo_v: &[Undo]) -> Vec<String> {
+    let mut script = Vec::new();
+    for Undo {
+        node,
+        code,
+        value_v,
+    } in undo_v
+    {
+        script.push(format!("{node}->{code} = _ _"));
+        for value in value_v {
+            script.push(format!("{node}->{code} append {node}->{code} {value}"));
+        }
+    }
+    script
+}
+
+/// The script with every new node made on a line of its own first, and appended to `collector`.
+fn record_new(script: &[String], collector: &str, nonce: &str) -> Vec<String> {
+    let mut recording = Vec::new();
+    for line in script {
+        let mut token_v = line
+            .split_whitespace()
+            .map(|token| token.to_string())
+            .collect::<Vec<String>>();
+        for (i, token) in token_v.iter_mut().enumerate().skip(2) {
+            if token == "?" {
+                let node = format!("$->$:new{i}_{nonce}");
+                recording.push(format!("{node} = ? _"));
+                recording.push(format!("{collector} append {collector} {node}"));
+                *token = node;
+            }
+        }
+        recording.push(token_v.join(" "));
+    }
+    recording
+}
+
+/// The script with the index of each line `i` recorded before it in `root->line_{nonce}`, so that
+/// a failure can be pointed at its line, and the nodes it writes in `root->written_{i}_{nonce}`
+/// with the values they held in `{node}->undo_{i}_{nonce}`.
+fn record(script: &[String], nonce: &str) -> Vec<String> {
+    let mut recording = Vec::new();
+    for (i, line) in script.iter().enumerate() {
+        recording.push(format!("root->line_{nonce} = {i} _"));
+        if let Some((base, code)) = written_attr(line) {
+            recording.push(format!("root->written_{i}_{nonce} = {base} _"));
+            recording.push(format!("{base}->undo_{i}_{nonce} = {base}->{code} _"));
+        }
+        recording.push(line.clone());
+    }
+    recording
+}
+
+/// Nodes and code of the attribute a line writes, temporaries aside.
+fn written_attr(line: &str) -> Option<(&str, &str)> {
+    let (base, code) = line.split_whitespace().next()?.rsplit_once("->")?;
+    if base == "$" || code.starts_with("$:") {
+        return None;
+    }
+    Some((base, code))
+}
+
+/// What the script recorded by [`record`] wrote, the values of each attribute as they
+/// were before it was first written, with the script clearing the records.
+async fn read_written(
+    global: &mut MemDataManager,
+    script: &[String],
+    nonce: &str,
+) -> err::Result<(Vec<Undo>, Vec<String>)> {
+    let mut undo_v: Vec<Undo> = Vec::new();
+    let mut clear_v = Vec::new();
+    for (i, line) in script.iter().enumerate() {
+        let code = match written_attr(line) {
+            Some((_, code)) => code,
+            None => continue,
+        };
+        let record = format!("root->written_{i}_{nonce}");
+        clear_v.push(format!("{record} = _ _"));
+        for node in get(global, &record).await? {
+            let undo = format!("{node}->undo_{i}_{nonce}");
+            let value_v = get(global, &undo).await?;
+            clear_v.push(format!("{undo} = _ _"));
+            if !undo_v
+                .iter()
+                .any(|written| written.node == node && written.code == code)
+            {
+                undo_v.push(Undo {
+                    node,
+                    code: code.to_string(),
+                    value_v,
+                });
+            }
+        }
+    }
+    Ok((undo_v, clear_v))
+}
+
+/// The failure of the script recorded by [`record`], at the line it failed at.
+async fn at_line(global: &mut MemDataManager, nonce: &str, e: err::Error) -> err::Error {
+    let line = get(global, &format!("root->line_{nonce}"))
+        .await
+        .ok()
+        .and_then(|line_v| line_v.first()?.parse().ok());
+    match line {
+        Some(line) => err::Error::Line(line, e.to_string()),
+        None => e,
+    }
+}
+
+/// Check what a script wrote against the scopes, see [`execute_scoped`].
+async fn check_written(
+    global: &mut MemDataManager,
+    written_v: &[Undo],
+    new_v: &[String],
+    scope_v: &[Scope],
+) -> err::Result<()> {
+    if scope_v.contains(&Scope::All) {
+        return Ok(());
+    }
+    // Nodes listed in root before the script.
+    let mut listed_mp = BTreeMap::new();
+    for code in auth::NODE_CODE_V {
+        let node_v = match written_v
+            .iter()
+            .find(|written| written.node == "root" && written.code == code)
+        {
+            Some(written) => written.value_v.clone(),
+            None => get(global, &format!("root->{code}")).await?,
+        };
+        listed_mp.insert(code, node_v);
+    }
+    // Whether the node is listed only where the scopes allow.
+    let in_scope = |node: &str| {
+        listed_mp
+            .iter()
+            .filter(|(_, node_v)| node_v.iter().any(|listed| listed == node))
+            .all(|(code, _)| auth::allow_write(scope_v, &format!("root->{code}")))
+    };
+    for written in written_v {
+        let path = format!("{}->{}", written.node, written.code);
+        let allowed = if written.node == "root" {
+            auth::allow_write(scope_v, &path)
+        } else {
+            new_v.contains(&written.node)
+                || (in_scope(&written.node)
+                    && listed_mp
+                        .values()
+                        .any(|node_v| node_v.contains(&written.node)))
+        };
+        if !allowed {
+            return Err(err::Error::Other(format!("{path} is out of scope")));
+        }
+        for value in get(global, &path).await? {
+            if written.value_v.contains(&value) || new_v.contains(&value) {
+                continue;
+            }
+            if value == "root" || !in_scope(&value) {
+                return Err(err::Error::Other(format!(
+                    "{value} may not be added to {path}"
+                )));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Values of the saved attributes now.
+async fn read(global: &mut MemDataManager, undo_v: &[Undo]) -> err::Result<Vec<Vec<String>>> {
+    let mut value_vv = Vec::new();
+    for undo in undo_v {
+        value_vv.push(get(global, &format!("{}->{}", undo.node, undo.code)).await?);
+    }
+    Ok(value_vv)
+}
+
+/// Edges added and removed, from the saved values and the values after the script.
+fn diff(undo_v: &[Undo], after_vv: &[Vec<String>]) -> (Vec<Edge>, Vec<Edge>) {
+    let mut added = Vec::new();
+    let mut removed = Vec::new();
+    for (undo, after_v) in undo_v.iter().zip(after_vv) {
+        let edge = |target: &str| Edge {
+            source: undo.node.clone(),
+            code: undo.code.clone(),
+            target: target.to_string(),
+        };
+        // Values are a list, an edge may be there more than once.
+        let mut before_v = undo.value_v.iter().collect::<Vec<&String>>();
+        for value in after_v {
+            match before_v.iter().position(|before| *before == value) {
+                Some(i) => {
+                    before_v.remove(i);
+                }
+                None => added.push(edge(value)),
+            }
+        }
+        removed.extend(before_v.into_iter().map(|value| edge(value)));
+    }
+    (added, removed)
+}
+
+async fn get(global: &mut MemDataManager, path: &str) -> err::Result<Vec<String>> {
+    global
+        .get(&Path::from_str(path))
+        .await
+        .map_err(|e| err::Error::Other(e.message().to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use edge_lib::util::{
+        data::{AsDataManager, MemDataManager},
+        Path,
+    };
+
+    use super::{diff, record, record_new, rollback_script, Edge, Undo};
+    use crate::util::{route, server::auth::Scope};
+
+    #[test]
+    fn test_rollback_script() {
+        let script = rollback_script(&[
+            Undo {
+                node: "root".to_string(),
+                code: "proxy".to_string(),
+                value_v: vec!["a".to_string(), "b".to_string()],
+            },
+            Undo {
+                node: "a".to_string(),
+                code: "name".to_string(),
+                value_v: Vec::new(),
+            },
+        ]);
+        assert_eq!(
+            script,
+            vec![
+                "root->proxy = _ _",
+                "root->proxy append root->proxy a",
+                "root->proxy append root->proxy b",
+                "a->name = _ _",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_diff() {
+        let undo_v = [Undo {
+            node: "root".to_string(),
+            code: "moon_server".to_string(),
+            value_v: vec!["a".to_string(), "b".to_string(), "b".to_string()],
+        }];
+        let (added, removed) = diff(&undo_v, &[vec!["b".to_string(), "c".to_string()]]);
+        let edge = |target: &str| Edge {
+            source: "root".to_string(),
+            code: "moon_server".to_string(),
+            target: target.to_string(),
+        };
+        assert_eq!(added, vec![edge("c")]);
+        assert_eq!(removed, vec![edge("a"), edge("b")]);
+    }
+
+    #[test]
+    fn test_record_new() {
+        let script = [
+            "$->$:proxy if $->$:proxy_exists ?",
+            "$->$:proxy->path = /web _",
+            "$->$:proxy->$:path = $->$:proxy->path _",
+        ]
+        .map(|s| s.to_string());
+        assert_eq!(
+            record_new(&script, "root->new", "n"),
+            vec![
+                "$->$:new3_n = ? _",
+                "root->new append root->new $->$:new3_n",
+                "$->$:proxy if $->$:proxy_exists $->$:new3_n",
+                "$->$:proxy->path = /web _",
+                "$->$:proxy->$:path = $->$:proxy->path _",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_record() {
+        let script = [
+            "$->$:proxy = root->proxy _",
+            "$->$:proxy->path = /web _",
+            "$->$:proxy->$:path = $->$:proxy->path _",
+        ]
+        .map(|s| s.to_string());
+        assert_eq!(
+            record(&script, "n"),
+            vec![
+                "root->line_n = 0 _",
+                "$->$:proxy = root->proxy _",
+                "root->line_n = 1 _",
+                "root->written_1_n = $->$:proxy _",
+                "$->$:proxy->undo_1_n = $->$:proxy->path _",
+                "$->$:proxy->path = /web _",
+                "root->line_n = 2 _",
+                "$->$:proxy->$:path = $->$:proxy->path _",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_execute() {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+            .block_on(async {
+                let mut global = MemDataManager::new(None);
+                let route = route::Route {
+                    path: "/api".to_string(),
+                    upstreams: vec!["http://127.0.0.1:8080".to_string()],
+                    ..Default::default()
+                };
+                route::put_route(&mut global, &route).await.unwrap();
+                super::execute(&mut global, &["root->lease = 30 _".to_string()])
+                    .await
+                    .unwrap();
+
+                // Fails at its last line, after writing root and a route.
+                let script = [
+                    "root->lease = 60 _",
+                    "$->$:proxy = root->proxy _",
+                    "$->$:proxy->path = /web _",
+                    "root->lease no_such_function 1 2",
+                ]
+                .map(|s| s.to_string());
+                let e = super::execute(&mut global, &script).await.unwrap_err();
+                assert_eq!(e.line(), Some(3));
+                let lease_v = global.get(&Path::from_str("root->lease")).await.unwrap();
+                assert_eq!(lease_v, vec!["30".to_string()]);
+                assert_eq!(
+                    route::list_routes(&mut global).await.unwrap(),
+                    vec![route.clone()]
+                );
+
+                // Writes through nodes found by value are rolled back too.
+                let script = [
+                    "root->lease = 60 _",
+                    "$->$:proxy = /api<-path _",
+                    "$->$:proxy->upstream = http://evil _",
+                    "root->lease no_such_function 1 2",
+                ]
+                .map(|s| s.to_string());
+                assert!(super::execute(&mut global, &script).await.is_err());
+                let lease_v = global.get(&Path::from_str("root->lease")).await.unwrap();
+                assert_eq!(lease_v, vec!["30".to_string()]);
+                assert_eq!(
+                    route::list_routes(&mut global).await.unwrap(),
+                    vec![route.clone()]
+                );
+
+                // A dry run leaves neither the values it wrote nor the nodes it made behind.
+                let script = [
+                    "root->lease = 60 _",
+                    "$->$:proxy = ? _",
+                    "$->$:proxy->path = /web _",
+                    "root->proxy append root->proxy $->$:proxy",
+                    "$->$:output = $->$:proxy _",
+                ]
+                .map(|s| s.to_string());
+                let dry_run = super::dry_run(&mut global, &script).await.unwrap();
+                let node = &dry_run.result[0];
+                assert!(dry_run.added.iter().any(|edge| edge.target == *node));
+                let lease_v = global.get(&Path::from_str("root->lease")).await.unwrap();
+                assert_eq!(lease_v, vec!["30".to_string()]);
+                assert_eq!(
+                    route::list_routes(&mut global).await.unwrap(),
+                    vec![route.clone()]
+                );
+                let path_v = global
+                    .get(&Path::from_str(&format!("{node}->path")))
+                    .await
+                    .unwrap();
+                assert!(path_v.is_empty());
+
+                // What scoped scripts really wrote is checked before it is kept.
+                let proxy_v = Scope::parse_list("write:proxy");
+                let script = ["root->proxy append root->proxy root".to_string()];
+                assert!(super::execute_scoped(&mut global, &script, &proxy_v)
+                    .await
+                    .is_err());
+                let script = [
+                    "$->$:proxy = root->proxy _",
+                    "$->$:proxy->upstream append $->$:proxy->upstream http://127.0.0.1:8081",
+                    "root->lease = 60 _",
+                ]
+                .map(|s| s.to_string());
+                assert!(super::execute_scoped(&mut global, &script, &proxy_v)
+                    .await
+                    .is_err());
+                assert_eq!(route::list_routes(&mut global).await.unwrap(), vec![route]);
+                super::execute_scoped(&mut global, &script[..2], &proxy_v)
+                    .await
+                    .unwrap();
+                let upstream_v = route::list_routes(&mut global).await.unwrap()[0]
+                    .upstreams
+                    .clone();
+                assert_eq!(upstream_v.len(), 2);
+                let lease_v = global.get(&Path::from_str("root->lease")).await.unwrap();
+                assert_eq!(lease_v, vec!["30".to_string()]);
+            });
+    }
+}